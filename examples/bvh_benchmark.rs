@@ -0,0 +1,142 @@
+// bvh_benchmark.rs
+// Times the flattened LinearBvh against pointer-based BvhNode trees on the spheres of
+// scenes/random_spheres.toml, with camera rays and one diffuse bounce from wherever they hit.
+// Splitting at the median is how scenes were built before the surface area heuristic; the
+// BvhNode built with the heuristic like the LinearBvh separates the gain of flattening from
+// that of the better splits.
+// Run with `cargo run --release --example bvh_benchmark`.
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rust_raytracer::bvh::{BvhNode, LinearBvh, SplitMethod};
+use rust_raytracer::hit::Hit;
+use rust_raytracer::material::Lambertian;
+use rust_raytracer::random;
+use rust_raytracer::ray::Ray;
+use rust_raytracer::scene;
+use rust_raytracer::sphere::Sphere;
+use rust_raytracer::vec::{Color, Vec3};
+use serde::Deserialize;
+
+const SCENE: &str = "scenes/random_spheres.toml";
+const CAMERA_RAYS: usize = 500_000;
+const ROUNDS: usize = 5;
+
+// Just the spheres of the scene description
+#[derive(Deserialize)]
+struct Description {
+    objects: Vec<Object>,
+}
+
+#[derive(Deserialize)]
+struct Object {
+    #[serde(rename = "type")]
+    kind: String,
+    center: Option<[f64; 3]>,
+    radius: Option<f64>,
+}
+
+// The spheres of the scene, all with the same material since only intersections are timed
+fn spheres() -> Vec<Arc<dyn Hit>> {
+    let source = fs::read_to_string(SCENE).expect("failed to read the scene");
+    let description: Description = toml::from_str(&source).expect("failed to parse the scene");
+    let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    description
+        .objects
+        .iter()
+        .filter(|object| object.kind == "sphere")
+        .map(|object| {
+            let [x, y, z] = object.center.unwrap();
+            Arc::new(Sphere::new(
+                Vec3::new(x, y, z),
+                object.radius.unwrap(),
+                material.clone(),
+            )) as Arc<dyn Hit>
+        })
+        .collect()
+}
+
+// Camera rays through random points of the image, followed by a bounce in a random direction
+// on the side of the normal wherever they hit something
+fn rays(world: &dyn Hit) -> Vec<Ray> {
+    let camera = scene::load(Path::new(SCENE))
+        .expect("failed to load the scene")
+        .camera;
+    random::seed(7);
+    let mut rays = Vec::with_capacity(2 * CAMERA_RAYS);
+    for _ in 0..CAMERA_RAYS {
+        let ray = camera.get_ray(random::random(), random::random());
+        if let Some(record) = world.hit(&ray, 0.001, f64::INFINITY) {
+            let direction = Vec3::random_in_hemisphere(record.normal);
+            rays.push(Ray::new(record.point, direction));
+        }
+        rays.push(ray);
+    }
+    rays
+}
+
+// Seconds for intersecting all rays, the fastest of several rounds, and the distances found
+fn time(world: &dyn Hit, rays: &[Ray]) -> (f64, Vec<Option<f64>>) {
+    let mut best = f64::INFINITY;
+    let mut distances = Vec::new();
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        distances = rays
+            .iter()
+            .map(|ray| {
+                world
+                    .hit(ray, 0.001, f64::INFINITY)
+                    .map(|record| record.time)
+            })
+            .collect();
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+    (best, distances)
+}
+
+// Milliseconds for building a hierarchy
+fn build<T>(build: impl FnOnce() -> T) -> (T, f64) {
+    let start = Instant::now();
+    let hierarchy = build();
+    (hierarchy, start.elapsed().as_secs_f64() * 1.0e3)
+}
+
+fn main() {
+    let spheres = spheres();
+    let (median, median_build) =
+        build(|| BvhNode::with_split_method(spheres.clone(), SplitMethod::Median));
+    let (tree, tree_build) = build(|| BvhNode::new(spheres.clone()));
+    let (linear, linear_build) = build(|| LinearBvh::new(spheres.clone()));
+
+    let rays = rays(&tree);
+    println!("{} spheres, {} rays", spheres.len(), rays.len());
+
+    let hierarchies: [(&str, &dyn Hit, f64); 3] = [
+        ("BvhNode, median", &median, median_build),
+        ("BvhNode, SAH", &tree, tree_build),
+        ("LinearBvh, SAH", &linear, linear_build),
+    ];
+    let mut times = Vec::new();
+    let mut expected = None;
+    for (name, world, build) in hierarchies {
+        let (seconds, distances) = time(world, &rays);
+        if expected.get_or_insert_with(|| distances.clone()) != &distances {
+            eprintln!("{} disagrees on the closest hits", name);
+            std::process::exit(1);
+        }
+        println!(
+            "{:16} built in {:7.3} ms, {:6.2} Mrays/s",
+            format!("{}:", name),
+            build,
+            rays.len() as f64 / seconds * 1.0e-6
+        );
+        times.push(seconds);
+    }
+    println!(
+        "LinearBvh speedup: {:.2}x over the median BvhNode, {:.2}x over the SAH BvhNode",
+        times[0] / times[2],
+        times[1] / times[2]
+    );
+}
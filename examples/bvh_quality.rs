@@ -0,0 +1,146 @@
+// bvh_quality.rs
+// Builds BVHs over a million small triangles, crowded unevenly into a few clusters, and compares
+// splitting at the median with the binned surface area heuristic: build time, expected cost
+// of a ray under the heuristic and measured ray throughput. Exits with an error if the
+// heuristic does not produce the cheaper tree or the trees disagree on any hit. The tree is
+// built both on one thread and on several, which must give the same tree.
+// Run with `cargo run --release --example bvh_quality`.
+use std::process;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rust_raytracer::bvh::{BvhNode, LinearBvh, SplitMethod};
+use rust_raytracer::hit::Hit;
+use rust_raytracer::material::Lambertian;
+use rust_raytracer::random;
+use rust_raytracer::ray::Ray;
+use rust_raytracer::triangle::Triangle;
+use rust_raytracer::vec::{Color, Vec3};
+
+const PRIMITIVES: usize = 1_000_000;
+const CLUSTERS: usize = 8;
+const RAYS: usize = 200_000;
+const THREADS: usize = 4;
+
+fn triangles() -> Vec<Arc<dyn Hit>> {
+    let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    let centers: Vec<Vec3> = (0..CLUSTERS).map(|_| Vec3::random(-100.0..100.0)).collect();
+    (0..PRIMITIVES)
+        .map(|i| {
+            // Later clusters are tighter, and a few triangles are spread over everything
+            let cluster = i % CLUSTERS;
+            let radius = if i % 50 == 0 {
+                100.0
+            } else {
+                40.0 / (1 + cluster) as f64
+            };
+            let center = if i % 50 == 0 {
+                Vec3::new(0.0, 0.0, 0.0)
+            } else {
+                centers[cluster]
+            };
+            let v0 = center + radius * Vec3::random_in_unit_sphere();
+            let v1 = v0 + 0.2 * Vec3::random_in_unit_sphere();
+            let v2 = v0 + 0.2 * Vec3::random_in_unit_sphere();
+            Arc::new(Triangle::new(v0, v1, v2, material.clone())) as Arc<dyn Hit>
+        })
+        .collect()
+}
+
+// Rays between random points of the scene, so they cross clusters and empty space alike
+fn rays() -> Vec<Ray> {
+    (0..RAYS)
+        .map(|_| {
+            let origin = Vec3::random(-150.0..150.0);
+            let target = Vec3::random(-100.0..100.0);
+            Ray::new(origin, target - origin)
+        })
+        .collect()
+}
+
+fn trace(world: &dyn Hit, rays: &[Ray]) -> (f64, Vec<Option<f64>>) {
+    let start = Instant::now();
+    let distances = rays
+        .iter()
+        .map(|ray| {
+            world
+                .hit(ray, 0.001, f64::INFINITY)
+                .map(|record| record.time)
+        })
+        .collect();
+    (start.elapsed().as_secs_f64(), distances)
+}
+
+fn main() {
+    random::seed(0);
+    let triangles = triangles();
+    let rays = rays();
+    println!("{} triangles, {} rays", triangles.len(), rays.len());
+    println!(
+        "{:<26}{:>12}{:>12}{:>12}{:>12}",
+        "", "build (ms)", "nodes", "SAH cost", "Mrays/s"
+    );
+
+    let serial = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .unwrap();
+    let parallel = rayon::ThreadPoolBuilder::new()
+        .num_threads(THREADS)
+        .build()
+        .unwrap();
+    let mut results = Vec::new();
+    for (method, threads) in [
+        (SplitMethod::Median, 1),
+        (SplitMethod::Sah, 1),
+        (SplitMethod::Sah, THREADS),
+    ] {
+        let pool = if threads == 1 { &serial } else { &parallel };
+        let start = Instant::now();
+        let bvh = pool.install(|| LinearBvh::with_split_method(triangles.clone(), method));
+        let build = start.elapsed().as_secs_f64();
+        let (seconds, distances) = trace(&bvh, &rays);
+        println!(
+            "{:<26}{:>12.1}{:>12}{:>12.2}{:>12.3}",
+            format!("{:?}, {} thread(s)", method, threads),
+            build * 1.0e3,
+            bvh.node_count(),
+            bvh.sah_cost(),
+            rays.len() as f64 / seconds * 1.0e-6
+        );
+        results.push((bvh.sah_cost(), distances));
+    }
+
+    let start = Instant::now();
+    let tree = parallel.install(|| BvhNode::new(triangles.clone()));
+    let build = start.elapsed().as_secs_f64();
+    let (seconds, distances) = trace(&tree, &rays);
+    println!(
+        "{:<26}{:>12.1}{:>12}{:>12}{:>12.3}",
+        format!("BvhNode, {} thread(s)", THREADS),
+        build * 1.0e3,
+        "",
+        "",
+        rays.len() as f64 / seconds * 1.0e-6
+    );
+
+    let (median_cost, median_distances) = &results[0];
+    let (sah_cost, sah_distances) = &results[1];
+    let (parallel_cost, parallel_distances) = &results[2];
+    let mut failed = false;
+    if parallel_cost.to_bits() != sah_cost.to_bits() || parallel_distances != sah_distances {
+        eprintln!("building on several threads gave a different tree");
+        failed = true;
+    }
+    if sah_distances != median_distances || &distances != sah_distances {
+        eprintln!("the trees disagree on the closest hits");
+        failed = true;
+    }
+    if sah_cost >= median_cost {
+        eprintln!("the surface area heuristic did not find a cheaper tree");
+        failed = true;
+    }
+    if failed {
+        process::exit(1);
+    }
+}
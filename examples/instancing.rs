@@ -0,0 +1,177 @@
+// instancing.rs
+// Animates a field of spinning copies of one mesh with the two-level acceleration structure:
+// the bottom level over the mesh is built once, and every frame only moves the instances and
+// rebuilds the top level. For comparison, also times one hierarchy over all triangles in world
+// space, which is what every frame would cost without instancing.
+// Run with `cargo run --release --example instancing`.
+use std::sync::Arc;
+use std::time::Instant;
+
+use rust_raytracer::bvh::BvhNode;
+use rust_raytracer::hit::Hit;
+use rust_raytracer::material::Lambertian;
+use rust_raytracer::random;
+use rust_raytracer::ray::Ray;
+use rust_raytracer::tlas::Tlas;
+use rust_raytracer::transform::Matrix4;
+use rust_raytracer::triangle::Triangle;
+use rust_raytracer::vec::{Color, Point3, Vec3};
+
+const GRID: usize = 24;
+const SUBDIVISIONS: usize = 3;
+const FRAMES: usize = 10;
+const RAYS: usize = 100_000;
+
+// Unit sphere approximated by subdividing the faces of an icosahedron
+fn icosphere(subdivisions: usize) -> Vec<[Point3; 3]> {
+    let t = (1.0 + 5.0_f64.sqrt()) / 2.0;
+    let v = [
+        (-1.0, t, 0.0),
+        (1.0, t, 0.0),
+        (-1.0, -t, 0.0),
+        (1.0, -t, 0.0),
+        (0.0, -1.0, t),
+        (0.0, 1.0, t),
+        (0.0, -1.0, -t),
+        (0.0, 1.0, -t),
+        (t, 0.0, -1.0),
+        (t, 0.0, 1.0),
+        (-t, 0.0, -1.0),
+        (-t, 0.0, 1.0),
+    ]
+    .map(|(x, y, z)| Vec3::new(x, y, z).normalized());
+    let faces = [
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+    let mut triangles: Vec<[Point3; 3]> = faces.iter().map(|f| f.map(|i| v[i])).collect();
+    for _ in 0..subdivisions {
+        triangles = triangles
+            .iter()
+            .flat_map(|&[a, b, c]| {
+                let ab = (a + b).normalized();
+                let bc = (b + c).normalized();
+                let ca = (c + a).normalized();
+                [[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]]
+            })
+            .collect();
+    }
+    triangles
+}
+
+// Placement of instance `index` at `frame`: on a grid, each spinning at its own rate
+fn placement(index: usize, frame: usize) -> Matrix4 {
+    let (row, column) = (index / GRID, index % GRID);
+    let offset = Vec3::new(3.0 * column as f64, 0.0, 3.0 * row as f64);
+    let angle = (frame * (1 + index % 7)) as f64 * 5.0;
+    Matrix4::translation(offset)
+        * Matrix4::rotation(Vec3::new(1.0, 1.0, 0.0), angle)
+        * Matrix4::scaling(Vec3::new(1.0, 0.6, 1.0))
+}
+
+// Rays from above into the field, the same ones every time
+fn rays() -> Vec<Ray> {
+    random::seed(1);
+    let extent = 3.0 * GRID as f64;
+    (0..RAYS)
+        .map(|_| {
+            let target = Vec3::new(
+                random::random_range(-1.0..extent),
+                0.0,
+                random::random_range(-1.0..extent),
+            );
+            let origin = Vec3::new(extent / 2.0, 50.0, -20.0);
+            Ray::new(origin, target - origin)
+        })
+        .collect()
+}
+
+fn count_hits(world: &dyn Hit, rays: &[Ray]) -> usize {
+    rays.iter()
+        .filter(|ray| world.hit(ray, 0.001, f64::INFINITY).is_some())
+        .count()
+}
+
+fn main() {
+    let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    let mesh = icosphere(SUBDIVISIONS);
+    let instances = GRID * GRID;
+    let rays = rays();
+    println!(
+        "{} instances of a mesh with {} triangles, {} triangles in total",
+        instances,
+        mesh.len(),
+        instances * mesh.len()
+    );
+
+    let start = Instant::now();
+    let mut tlas = Tlas::new();
+    let shapes = mesh
+        .iter()
+        .map(|&[v0, v1, v2]| Arc::new(Triangle::new(v0, v1, v2, material.clone())) as Arc<dyn Hit>)
+        .collect();
+    let blas = tlas.add_blas(shapes).unwrap();
+    for index in 0..instances {
+        tlas.add_instance(blas, placement(index, 0)).unwrap();
+    }
+    tlas.rebuild();
+    println!(
+        "bottom level and first frame: {:8.2} ms",
+        start.elapsed().as_secs_f64() * 1.0e3
+    );
+
+    let start = Instant::now();
+    let mut triangles: Vec<Arc<dyn Hit>> = Vec::new();
+    for index in 0..instances {
+        let transform = placement(index, 0);
+        for &vertices in &mesh {
+            let [v0, v1, v2] = vertices.map(|v| transform.transform_point(v));
+            triangles.push(Arc::new(Triangle::new(v0, v1, v2, material.clone())));
+        }
+    }
+    let flat = BvhNode::new(triangles);
+    println!(
+        "one hierarchy over all:       {:8.2} ms",
+        start.elapsed().as_secs_f64() * 1.0e3
+    );
+
+    // Both structures hold the same surfaces, so they must agree on what the rays hit
+    let (two_level_hits, flat_hits) = (count_hits(&tlas, &rays), count_hits(&flat, &rays));
+    println!("hits: {} two-level, {} flat", two_level_hits, flat_hits);
+
+    let mut rebuild_time = 0.0;
+    let mut hits = 0;
+    for frame in 1..=FRAMES {
+        let start = Instant::now();
+        for index in 0..instances {
+            tlas.set_transform(index, placement(index, frame));
+        }
+        tlas.rebuild();
+        rebuild_time += start.elapsed().as_secs_f64();
+        hits += count_hits(&tlas, &rays);
+    }
+    println!(
+        "moving all instances:         {:8.2} ms per frame ({} hits over {} frames)",
+        rebuild_time * 1.0e3 / FRAMES as f64,
+        hits,
+        FRAMES
+    );
+}
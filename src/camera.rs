@@ -2,6 +2,7 @@
 use super::ray::Ray;
 use super::vec::{Point3, Vec3};
 
+#[derive(Clone, Copy)]
 pub struct Camera {
     origin: Point3,
     llc: Point3,
@@ -10,6 +11,11 @@ pub struct Camera {
     cu: Vec3,
     cv: Vec3,
     lens_radius: f64,
+    // Construction parameters, kept so the camera can be rebuilt for another aspect ratio
+    look_at: Point3,
+    v_up: Vec3,
+    v_fow: f64,
+    focus_dist: f64,
 }
 
 impl Camera {
@@ -41,9 +47,26 @@ impl Camera {
             cu,
             cv,
             lens_radius: aperture / 2.0,
+            look_at,
+            v_up,
+            v_fow,
+            focus_dist,
         }
     }
 
+    // Same placement and lens, with the horizontal extent adjusted to `aspect_ratio`
+    pub fn with_aspect_ratio(&self, aspect_ratio: f64) -> Camera {
+        Camera::new(
+            self.origin,
+            self.look_at,
+            self.v_up,
+            self.v_fow,
+            aspect_ratio,
+            2.0 * self.lens_radius,
+            self.focus_dist,
+        )
+    }
+
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
         let radius = self.lens_radius * Vec3::random_in_unit_disk();
         let offset = self.cu * radius.x() + self.cv * radius.y();
@@ -0,0 +1,372 @@
+// cli.rs
+use rust_raytracer::output::Format;
+use rust_raytracer::render::RenderSettings;
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: rust_raytracer [OPTIONS]
+
+Renders a scene description to an image file.
+
+Options:
+  -i, --scene <FILE>         Scene description to render [default: scenes/random_spheres.toml]
+  -o, --output <FILE>        Image file to write [default: image.ppm]
+  -f, --format <FORMAT>      Image format [default: from the extension, ppm-ascii for .ppm]
+                               png, png16, ppm (binary P6): tone mapped to sRGB
+                               ppm-ascii (P3): square root gamma, as before the other formats
+                               hdr, pfm, exr (ZIP), exr-uncompressed: linear radiance
+      --width <PIXELS>       Image width; the height follows the aspect ratio if omitted
+      --height <PIXELS>      Image height; the width follows the aspect ratio if omitted
+      --aspect-ratio <W:H>   Aspect ratio as `16:9` or `1.5` [default: from the scene]
+  -s, --samples <COUNT>      Samples per pixel [default: from the scene]
+  -d, --max-depth <COUNT>    Maximum number of bounces per path [default: from the scene]
+      --seed <NUMBER>        Seed for the random number generators [default: from the scene]
+      --spectral             Render with sampled wavelengths for dispersion [default: from the scene]
+  -j, --threads <COUNT>      Number of render threads, 0 uses every core [default: 0]
+  -h, --help                 Print this message
+";
+
+pub struct Options {
+    pub scene: PathBuf,
+    pub output: PathBuf,
+    pub format: Format,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub aspect_ratio: Option<f64>,
+    pub samples_per_pixel: Option<u64>,
+    pub max_depth: Option<u64>,
+    pub seed: Option<u64>,
+    pub spectral: bool,
+    pub threads: usize,
+}
+
+pub enum Command {
+    Render(Options),
+    Help,
+}
+
+// Parses the program arguments, without the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut format = None;
+    let mut options = Options {
+        scene: PathBuf::from("scenes/random_spheres.toml"),
+        output: PathBuf::from("image.ppm"),
+        format: Format::PpmAscii,
+        width: None,
+        height: None,
+        aspect_ratio: None,
+        samples_per_pixel: None,
+        max_depth: None,
+        seed: None,
+        spectral: false,
+        threads: 0,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        }
+
+        // Accept both `--flag value` and `--flag=value`
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || -> Result<String, String> {
+            match inline_value {
+                Some(value) => Ok(value.to_string()),
+                None => args
+                    .next()
+                    .ok_or_else(|| format!("{} expects a value", flag)),
+            }
+        };
+
+        match flag.as_str() {
+            "-i" | "--scene" => options.scene = PathBuf::from(value()?),
+            "-o" | "--output" => options.output = PathBuf::from(value()?),
+            "-f" | "--format" => {
+                let name = value()?;
+                format = Some(Format::from_name(&name).ok_or_else(|| {
+                    format!(
+                        "unknown format `{}`, expected one of {}",
+                        name,
+                        Format::NAMES.join(", ")
+                    )
+                })?);
+            }
+            "--width" => options.width = Some(positive(&flag, &value()?)?),
+            "--height" => options.height = Some(positive(&flag, &value()?)?),
+            "--aspect-ratio" => options.aspect_ratio = Some(aspect_ratio(&value()?)?),
+            "-s" | "--samples" => {
+                options.samples_per_pixel = Some(positive(&flag, &value()?)? as u64)
+            }
+            "-d" | "--max-depth" => options.max_depth = Some(number(&flag, &value()?)?),
+            "--seed" => options.seed = Some(number(&flag, &value()?)?),
+            "--spectral" => options.spectral = true,
+            "-j" | "--threads" => options.threads = number(&flag, &value()?)?,
+            _ => return Err(format!("unknown argument `{}`", arg)),
+        }
+    }
+
+    options.format = match format.or_else(|| Format::from_path(&options.output)) {
+        Some(format) => format,
+        None => {
+            return Err(format!(
+                "cannot tell the image format from `{}`, use --format",
+                options.output.display()
+            ))
+        }
+    };
+
+    Ok(Command::Render(options))
+}
+
+// Largest image the command line accepts, 8192x8192 or the same number of pixels in any other
+// shape, whose framebuffer already takes 1.5 GiB
+const MAX_PIXELS: usize = 1 << 26;
+
+impl Options {
+    // Overrides the scene's render settings and resolves the final image size.
+    // Returns the aspect ratio the camera has to use.
+    pub fn apply(&self, settings: &mut RenderSettings) -> Result<f64, String> {
+        let scene_aspect_ratio = (settings.width as f64) / (settings.height as f64);
+        let aspect_ratio = self.aspect_ratio.unwrap_or(scene_aspect_ratio);
+
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => {
+                // Allow for the rounding of either dimension to whole pixels
+                if self.aspect_ratio.is_some()
+                    && ((width as f64) - (height as f64) * aspect_ratio).abs()
+                        > aspect_ratio.max(1.0)
+                {
+                    return Err(format!(
+                        "--width {} and --height {} do not match --aspect-ratio {}",
+                        width, height, aspect_ratio
+                    ));
+                }
+                (width, height)
+            }
+            (Some(width), None) => (width, ((width as f64) / aspect_ratio).round() as usize),
+            (None, Some(height)) => (((height as f64) * aspect_ratio).round() as usize, height),
+            (None, None) => (
+                settings.width,
+                ((settings.width as f64) / aspect_ratio).round() as usize,
+            ),
+        };
+        if width < 2 || height < 2 {
+            return Err(format!(
+                "the image must be at least 2x2 pixels, got {}x{}",
+                width, height
+            ));
+        }
+        if width
+            .checked_mul(height)
+            .is_none_or(|pixels| pixels > MAX_PIXELS)
+        {
+            return Err(format!(
+                "the image must have at most {} pixels, got {}x{}",
+                MAX_PIXELS, width, height
+            ));
+        }
+
+        settings.width = width;
+        settings.height = height;
+        if let Some(samples_per_pixel) = self.samples_per_pixel {
+            settings.samples_per_pixel = samples_per_pixel;
+        }
+        if let Some(max_depth) = self.max_depth {
+            settings.max_depth = max_depth;
+        }
+        if let Some(seed) = self.seed {
+            settings.seed = seed;
+        }
+        if self.spectral {
+            settings.spectral = true;
+        }
+
+        Ok((width as f64) / (height as f64))
+    }
+}
+
+fn number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a non-negative integer, got `{}`", flag, value))
+}
+
+fn positive(flag: &str, value: &str) -> Result<usize, String> {
+    match number(flag, value)? {
+        0 => Err(format!("{} must be greater than zero", flag)),
+        value => Ok(value),
+    }
+}
+
+fn aspect_ratio(value: &str) -> Result<f64, String> {
+    let invalid = || format!("--aspect-ratio expects `W:H` or a number, got `{}`", value);
+    let ratio = match value.split_once(':') {
+        Some((width, height)) => {
+            let width: f64 = width.trim().parse().map_err(|_| invalid())?;
+            let height: f64 = height.trim().parse().map_err(|_| invalid())?;
+            width / height
+        }
+        None => value.trim().parse().map_err(|_| invalid())?,
+    };
+    if !ratio.is_finite() || ratio <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn options(args: &[&str]) -> Options {
+        match parse_args(args) {
+            Ok(Command::Render(options)) => options,
+            Ok(Command::Help) => panic!("{:?} asked for help", args),
+            Err(error) => panic!("{:?} were rejected: {}", args, error),
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse_args(args) {
+            Err(error) => error,
+            Ok(_) => panic!("{:?} were accepted", args),
+        }
+    }
+
+    fn settings(width: usize, height: usize) -> RenderSettings {
+        RenderSettings {
+            width,
+            height,
+            samples_per_pixel: 10,
+            max_depth: 50,
+            seed: 0,
+            spectral: false,
+        }
+    }
+
+    #[test]
+    fn defaults_and_both_value_spellings() {
+        let defaults = options(&[]);
+        assert_eq!(defaults.output, PathBuf::from("image.ppm"));
+        assert_eq!(defaults.format, Format::PpmAscii);
+        assert_eq!(defaults.threads, 0);
+
+        let options = options(&["-s", "16", "--width=400", "-o", "out.png", "--seed", "7"]);
+        assert_eq!(options.samples_per_pixel, Some(16));
+        assert_eq!(options.width, Some(400));
+        assert_eq!(options.format, Format::Png);
+        assert_eq!(options.seed, Some(7));
+        assert!(matches!(
+            parse_args(&["--width", "4", "--help"]),
+            Ok(Command::Help)
+        ));
+    }
+
+    #[test]
+    fn formats_come_from_the_flag_before_the_extension() {
+        assert_eq!(options(&["-o", "out.exr"]).format, Format::Exr);
+        assert_eq!(options(&["-o", "out.PNG"]).format, Format::Png);
+        assert_eq!(
+            options(&["-o", "out.png", "-f", "png16"]).format,
+            Format::Png16
+        );
+        assert_eq!(options(&["-o", "out.ppm", "-f", "p6"]).format, Format::Ppm);
+        assert_eq!(
+            options(&["-o", "image", "--format=hdr"]).format,
+            Format::Hdr
+        );
+        assert_eq!(
+            error(&["-o", "image.tiff"]),
+            "cannot tell the image format from `image.tiff`, use --format"
+        );
+        assert!(error(&["-f", "gif"]).starts_with("unknown format `gif`, expected one of png,"));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert_eq!(error(&["--width"]), "--width expects a value");
+        assert_eq!(
+            error(&["--width", "0"]),
+            "--width must be greater than zero"
+        );
+        assert_eq!(
+            error(&["-s", "-1"]),
+            "-s expects a non-negative integer, got `-1`"
+        );
+        assert_eq!(
+            error(&["--threads", "many"]),
+            "--threads expects a non-negative integer, got `many`"
+        );
+        assert_eq!(error(&["--bogus"]), "unknown argument `--bogus`");
+        assert_eq!(error(&["--bogus=1"]), "unknown argument `--bogus=1`");
+        for ratio in ["16:0", "0", "-1.5", "wide", "16:x"] {
+            assert_eq!(
+                error(&["--aspect-ratio", ratio]),
+                format!("--aspect-ratio expects `W:H` or a number, got `{}`", ratio)
+            );
+        }
+    }
+
+    #[test]
+    fn sizes_follow_the_aspect_ratio() {
+        let mut scene = settings(400, 200);
+        assert_eq!(options(&["--width", "100"]).apply(&mut scene), Ok(2.0));
+        assert_eq!((scene.width, scene.height), (100, 50));
+
+        let mut scene = settings(400, 200);
+        let options_16_9 = options(&["--height", "90", "--aspect-ratio", "16:9"]);
+        let ratio = options_16_9.apply(&mut scene).unwrap();
+        assert_eq!((scene.width, scene.height), (160, 90));
+        assert_eq!(ratio, 160.0 / 90.0);
+
+        let mut scene = settings(400, 200);
+        let overrides = options(&["-s", "3", "-d", "4", "--seed", "5", "--spectral"]);
+        overrides.apply(&mut scene).unwrap();
+        assert_eq!(
+            (scene.samples_per_pixel, scene.max_depth, scene.seed),
+            (3, 4, 5)
+        );
+        assert!(scene.spectral);
+    }
+
+    #[test]
+    fn inconsistent_sizes_are_rejected() {
+        let mismatch = options(&["--width", "100", "--height", "100", "--aspect-ratio", "2"]);
+        assert_eq!(
+            mismatch.apply(&mut settings(400, 200)),
+            Err("--width 100 and --height 100 do not match --aspect-ratio 2".to_string())
+        );
+        // Rounding to whole pixels is allowed for
+        let rounded = options(&["--width", "101", "--height", "50", "--aspect-ratio", "2"]);
+        assert!(rounded.apply(&mut settings(400, 200)).is_ok());
+
+        let tiny = options(&["--width", "3", "--aspect-ratio", "4"]);
+        assert_eq!(
+            tiny.apply(&mut settings(400, 200)),
+            Err("the image must be at least 2x2 pixels, got 3x1".to_string())
+        );
+
+        let largest = options(&["--width", "8192", "--height", "8192"]);
+        assert!(largest.apply(&mut settings(400, 200)).is_ok());
+        let huge = options(&["--width", "8193", "--height", "8192"]);
+        assert_eq!(
+            huge.apply(&mut settings(400, 200)),
+            Err("the image must have at most 67108864 pixels, got 8193x8192".to_string())
+        );
+        // Sizes whose product does not even fit in a usize
+        let overflowing = format!("{}", usize::MAX / 2);
+        let overflowing = options(&["--width", &overflowing, "--height", &overflowing]);
+        assert!(overflowing.apply(&mut settings(400, 200)).is_err());
+        let wide = options(&["--width", &format!("{}", usize::MAX)]);
+        assert!(wide.apply(&mut settings(400, 200)).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+// environment.rs
+use super::image::Image;
+use super::random::random;
+use super::sampling::Distribution;
+use super::vec::{Color, Vec3};
+
+use std::f64::consts::PI;
+
+// Radiance arriving from infinitely far away, seen by every ray that leaves the scene
+pub trait Environment: Send + Sync {
+    fn radiance(&self, direction: Vec3) -> Color;
+
+    // Picks a unit direction towards the environment with its solid angle density.
+    // Environments without a useful distribution are only reached by scattered rays.
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        None
+    }
+
+    // Solid angle density with which `sample` picks `direction`
+    fn pdf(&self, _direction: Vec3) -> f64 {
+        0.0
+    }
+}
+
+pub struct SolidColor {
+    color: Color,
+}
+
+impl SolidColor {
+    pub fn new(color: Color) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+impl Environment for SolidColor {
+    fn radiance(&self, _direction: Vec3) -> Color {
+        self.color
+    }
+}
+
+// Vertical blend from `bottom` straight down to `top` straight up
+pub struct Gradient {
+    bottom: Color,
+    top: Color,
+}
+
+impl Gradient {
+    pub fn new(bottom: Color, top: Color) -> Gradient {
+        Gradient { bottom, top }
+    }
+}
+
+impl Default for Gradient {
+    // The white to blue sky of the book
+    fn default() -> Gradient {
+        Gradient::new(Color::new(1.0, 1.0, 1.0), Color::new(0.5, 0.7, 1.0))
+    }
+}
+
+impl Environment for Gradient {
+    fn radiance(&self, direction: Vec3) -> Color {
+        let t = 0.5 * (direction.normalized().y() + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+}
+
+// Latitude-longitude image covering the whole sphere of directions, +y up.
+// The center column looks down -z before rotation.
+pub struct EquirectangularMap {
+    image: Image,
+    intensity: f64,
+    // Rotation about +y
+    sin_rotation: f64,
+    cos_rotation: f64,
+    // Texels are sampled by luminance: first a row from the marginal distribution,
+    // then a column from that row's conditional distribution
+    rows: Distribution,
+    columns: Vec<Distribution>,
+}
+
+impl EquirectangularMap {
+    // `rotation` in degrees turns the map counter-clockwise about +y, seen from above
+    pub fn new(image: Image, rotation: f64, intensity: f64) -> EquirectangularMap {
+        let height = image.height();
+        // Rows near the poles cover less solid angle than rows near the horizon
+        let columns: Vec<Distribution> = image
+            .rows()
+            .enumerate()
+            .map(|(y, row)| {
+                let sin_theta = (PI * (y as f64 + 0.5) / height as f64).sin();
+                let weights: Vec<f64> = row
+                    .iter()
+                    .map(|pixel| pixel.luminance().max(0.0) * sin_theta)
+                    .collect();
+                Distribution::new(&weights)
+            })
+            .collect();
+        let rows = Distribution::new(&columns.iter().map(|row| row.total()).collect::<Vec<_>>());
+
+        let rotation = rotation.to_radians();
+        EquirectangularMap {
+            image,
+            intensity,
+            sin_rotation: rotation.sin(),
+            cos_rotation: rotation.cos(),
+            rows,
+            columns,
+        }
+    }
+
+    // Texture coordinates in [0, 1), v from the top of the image
+    fn uv(&self, direction: Vec3) -> (f64, f64) {
+        let direction = direction.normalized();
+        // Undo the map's rotation
+        let x = self.cos_rotation * direction.x() - self.sin_rotation * direction.z();
+        let z = self.sin_rotation * direction.x() + self.cos_rotation * direction.z();
+
+        let phi = x.atan2(-z) + PI;
+        let theta = direction.y().clamp(-1.0, 1.0).acos();
+        ((phi / (2.0 * PI)).fract(), theta / PI)
+    }
+
+    // Inverse of `uv`
+    fn direction(&self, u: f64, v: f64) -> Vec3 {
+        let phi = 2.0 * PI * u - PI;
+        let theta = PI * v;
+        let (x, y, z) = (
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        );
+        Vec3::new(
+            self.cos_rotation * x + self.sin_rotation * z,
+            y,
+            -self.sin_rotation * x + self.cos_rotation * z,
+        )
+    }
+
+    fn texel(&self, u: f64, v: f64) -> (usize, usize) {
+        let x = ((u * self.image.width() as f64) as usize).min(self.image.width() - 1);
+        let y = ((v * self.image.height() as f64) as usize).min(self.image.height() - 1);
+        (x, y)
+    }
+
+    // Converts the probability of a texel to a density over solid angle at height `v`
+    fn solid_angle_pdf(&self, probability: f64, v: f64) -> f64 {
+        let sin_theta = (PI * v).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let texels = (self.image.width() * self.image.height()) as f64;
+        probability * texels / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Environment for EquirectangularMap {
+    fn radiance(&self, direction: Vec3) -> Color {
+        let (u, v) = self.uv(direction);
+        let (x, y) = self.texel(u, v);
+        self.intensity * self.image.pixel(x, y)
+    }
+
+    fn sample(&self) -> Option<(Vec3, f64)> {
+        if self.rows.total() <= 0.0 {
+            return None;
+        }
+        let (y, row_probability) = self.rows.sample(random());
+        let (x, column_probability) = self.columns[y].sample(random());
+
+        let u = (x as f64 + random()) / self.image.width() as f64;
+        let v = (y as f64 + random()) / self.image.height() as f64;
+        let pdf = self.solid_angle_pdf(row_probability * column_probability, v);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((self.direction(u, v), pdf))
+    }
+
+    fn pdf(&self, direction: Vec3) -> f64 {
+        if self.rows.total() <= 0.0 {
+            return 0.0;
+        }
+        let (u, v) = self.uv(direction);
+        let (x, y) = self.texel(u, v);
+        self.solid_angle_pdf(self.rows.probability(y) * self.columns[y].probability(x), v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    // Uneven brightness, with a bright band below the horizon and a dark column
+    fn map(rotation: f64) -> EquirectangularMap {
+        let (width, height) = (16, 8);
+        let pixels = (0..width * height)
+            .map(|index| {
+                let (x, y) = (index % width, index / width);
+                let bright = if y == 5 { 4.0 } else { 0.0 };
+                let dark = if x == 3 { 0.0 } else { 1.0 };
+                Color::new(1.0, 0.5, 0.25) * (dark * (0.5 + x as f64 / 8.0) + bright)
+            })
+            .collect();
+        EquirectangularMap::new(Image::from_pixels(width, height, pixels), rotation, 1.0)
+    }
+
+    #[test]
+    fn directions_and_texture_coordinates_round_trip() {
+        for rotation in [0.0, 30.0, -135.0] {
+            let map = map(rotation);
+            for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.75, 0.9), (0.99, 0.01)] {
+                let direction = map.direction(u, v);
+                assert!((direction.length() - 1.0).abs() < 1.0e-12);
+                let (u2, v2) = map.uv(direction);
+                assert!((u2 - u).abs() < 1.0e-9 && (v2 - v).abs() < 1.0e-9);
+            }
+        }
+        // Before rotation, the middle of the image looks down -z and its top straight up
+        let direction = map(0.0).direction(0.5, 0.5);
+        assert!((direction - Vec3::new(0.0, 0.0, -1.0)).length() < 1.0e-12);
+        assert!(map(0.0).direction(0.3, 0.0).y() > 1.0 - 1.0e-12);
+    }
+
+    #[test]
+    fn samples_report_the_density_of_their_direction() {
+        random::seed(3);
+        let map = map(30.0);
+        for _ in 0..10_000 {
+            let (direction, pdf) = map.sample().unwrap();
+            assert!(pdf > 0.0);
+            let expected = map.pdf(direction);
+            assert!(
+                (pdf / expected - 1.0).abs() < 1.0e-6,
+                "sampled with {}, pdf gives {}",
+                pdf,
+                expected
+            );
+            assert!(map.radiance(direction).luminance() > 0.0);
+        }
+    }
+
+    #[test]
+    fn density_integrates_to_one() {
+        random::seed(4);
+        let map = map(30.0);
+        let samples = 400_000;
+        let (mut integral, mut radiance) = (0.0, 0.0);
+        for _ in 0..samples {
+            let direction = Vec3::random_in_unit_sphere().normalized();
+            integral += map.pdf(direction);
+            radiance += map.radiance(direction).luminance();
+        }
+        let solid_angle = 4.0 * PI / samples as f64;
+        let integral = integral * solid_angle;
+        assert!(
+            (integral - 1.0).abs() < 0.01,
+            "pdf integrates to {}",
+            integral
+        );
+
+        // Importance sampling has to find the same total light
+        let mut sampled = 0.0;
+        for _ in 0..samples {
+            let (direction, pdf) = map.sample().unwrap();
+            sampled += map.radiance(direction).luminance() / pdf;
+        }
+        let (radiance, sampled) = (radiance * solid_angle, sampled / samples as f64);
+        assert!((sampled / radiance - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn black_maps_are_not_sampled() {
+        let black = Image::from_pixels(4, 2, vec![Color::new(0.0, 0.0, 0.0); 8]);
+        let map = EquirectangularMap::new(black, 0.0, 1.0);
+        assert!(map.sample().is_none());
+        assert_eq!(map.pdf(Vec3::new(0.0, 1.0, 0.0)), 0.0);
+    }
+}
@@ -6,22 +6,48 @@ use super::material::Scatter;
 use super::ray::Ray;
 use super::vec::{Point3, Vec3};
 
+#[derive(Clone)]
 pub struct HitRecord {
     pub point: Point3,
+    // Normal used for shading, on the side of the incoming ray. Interpolated vertex normals
+    // make it differ from the geometric normal.
     pub normal: Vec3,
+    // Normal of the actual surface, on the same side as `normal`
+    pub geometric_normal: Vec3,
     pub material: Arc<dyn Scatter>,
     pub time: f64,
     pub front_face: bool,
+    // Surface coordinates; barycentric coordinates for triangles without texture coordinates
+    pub u: f64,
+    pub v: f64,
+    // Partial derivatives of the point with respect to u and v, not normalized
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // Shape that was hit, as numbered by whoever built the scene with `with_id` (zero for
+    // shapes without a number), and the face within it for meshes
+    pub object_id: u32,
+    pub primitive_id: u32,
 }
 
 impl HitRecord {
+    // Sets both normals from the geometric normal pointing out of the surface
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: Vec3) {
         self.front_face = ray.direction().dot(outward_normal) < 0.0;
-        self.normal = if self.front_face {
+        self.geometric_normal = if self.front_face {
             outward_normal
         } else {
             (-1.0) * outward_normal
         };
+        self.normal = self.geometric_normal;
+    }
+
+    // Replaces the shading normal, flipped if needed to stay on the side of the geometric normal
+    pub fn set_shading_normal(&mut self, shading_normal: Vec3) {
+        self.normal = if shading_normal.dot(self.geometric_normal) < 0.0 {
+            (-1.0) * shading_normal
+        } else {
+            shading_normal
+        };
     }
 }
 
@@ -29,6 +55,17 @@ pub trait Hit: Send + Sync {
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
 
     fn bounding_box(&self) -> Option<Aabb>;
+
+    // Solid angle density with which `random` picks `direction` from `origin`.
+    // Shapes that cannot be sampled as lights keep the default of zero.
+    fn pdf_value(&self, _origin: Point3, _direction: Vec3) -> f64 {
+        0.0
+    }
+
+    // Direction from `origin` towards a random point on the shape
+    fn random(&self, _origin: Point3) -> Vec3 {
+        Vec3::new(1.0, 0.0, 0.0)
+    }
 }
 
 pub type World = Vec<Box<dyn Hit>>;
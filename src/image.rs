@@ -0,0 +1,60 @@
+// image.rs
+use super::vec::Color;
+
+// Linear radiance per pixel, stored row by row starting at the top of the image
+#[derive(Clone)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+impl Image {
+    // Images have at least one pixel, which lookups into textures and environment maps rely on
+    pub fn new(width: usize, height: usize) -> Image {
+        assert!(width > 0 && height > 0, "image has no pixels");
+        Image {
+            width,
+            height,
+            pixels: vec![Color::new(0.0, 0.0, 0.0); width * height],
+        }
+    }
+
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Color>) -> Image {
+        assert!(width > 0 && height > 0, "image has no pixels");
+        assert_eq!(
+            pixels.len(),
+            width * height,
+            "pixel count does not match size"
+        );
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn rows(&self) -> std::slice::Chunks<'_, Color> {
+        self.pixels.chunks(self.width)
+    }
+}
@@ -0,0 +1,373 @@
+// input.rs
+use super::image::Image;
+use super::vec::Color;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+// Reads an image into linear radiance, choosing the decoder from the file extension.
+// 8 and 16-bit formats are assumed to be sRGB encoded.
+pub fn read(path: &Path) -> io::Result<Image> {
+    read_with(path, true)
+}
+
+// Reads an image of data rather than colors, like a normal or height map, keeping the
+// samples of 8 and 16-bit formats as they are, scaled to [0, 1]
+pub fn read_linear(path: &Path) -> io::Result<Image> {
+    read_with(path, false)
+}
+
+fn read_with(path: &Path, srgb: bool) -> io::Result<Image> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("png") => read_png(path, srgb),
+        Some("ppm") => read_ppm(&fs::read(path)?, srgb),
+        Some("hdr") | Some("pic") => read_hdr(&fs::read(path)?),
+        _ => Err(invalid(format!(
+            "unsupported image format `{}`",
+            path.display()
+        ))),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Textures and environment maps look up their nearest pixels, so there must be some
+fn image(width: usize, height: usize, pixels: Vec<Color>) -> io::Result<Image> {
+    if width == 0 || height == 0 {
+        return Err(invalid(format!(
+            "empty image of {}x{} pixels",
+            width, height
+        )));
+    }
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+fn read_png(path: &Path, srgb: bool) -> io::Result<Image> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Palettes and bit depths below 8 are expanded to plain 8-bit samples
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(io::Error::other)?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data).map_err(io::Error::other)?;
+
+    let samples: Vec<f64> = match info.bit_depth {
+        png::BitDepth::Sixteen => data[..info.buffer_size()]
+            .chunks_exact(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as f64 / 65535.0)
+            .collect(),
+        _ => data[..info.buffer_size()]
+            .iter()
+            .map(|&byte| byte as f64 / 255.0)
+            .collect(),
+    };
+
+    let decode = |value: f64| if srgb { decode_srgb(value) } else { value };
+    let channels = info.color_type.samples();
+    let pixels = samples
+        .chunks_exact(channels)
+        .map(|sample| match channels {
+            // Grayscale, with or without alpha
+            1 | 2 => {
+                let value = decode(sample[0]);
+                Color::new(value, value, value)
+            }
+            _ => Color::new(decode(sample[0]), decode(sample[1]), decode(sample[2])),
+        })
+        .collect();
+
+    image(info.width as usize, info.height as usize, pixels)
+}
+
+// Binary (P6) and ASCII (P3) portable pixmaps with up to 16 bits per sample
+fn read_ppm(data: &[u8], srgb: bool) -> io::Result<Image> {
+    let mut position = 0;
+    // Header fields are separated by whitespace, comments run to the end of the line
+    let mut next_token = || -> io::Result<String> {
+        loop {
+            match data.get(position) {
+                Some(b'#') => {
+                    while data.get(position).is_some_and(|&byte| byte != b'\n') {
+                        position += 1;
+                    }
+                }
+                Some(byte) if byte.is_ascii_whitespace() => position += 1,
+                Some(_) => break,
+                None => return Err(invalid("truncated PPM file".to_string())),
+            }
+        }
+        let start = position;
+        while data
+            .get(position)
+            .is_some_and(|byte| !byte.is_ascii_whitespace())
+        {
+            position += 1;
+        }
+        Ok(String::from_utf8_lossy(&data[start..position]).into_owned())
+    };
+
+    let magic = next_token()?;
+    if magic != "P3" && magic != "P6" {
+        return Err(invalid(format!("unsupported PPM type `{}`", magic)));
+    }
+    let mut number = |name: &str| -> io::Result<usize> {
+        let token = next_token()?;
+        token
+            .parse()
+            .map_err(|_| invalid(format!("invalid PPM {} `{}`", name, token)))
+    };
+    let width = number("width")?;
+    let height = number("height")?;
+    let max_value = number("maximum value")?;
+    if !(1..=65535).contains(&max_value) {
+        return Err(invalid(format!("invalid PPM maximum value {}", max_value)));
+    }
+
+    // Every sample takes at least one byte, so sizes beyond the file are rejected before
+    // anything is allocated for them
+    let truncated = || invalid("truncated PPM pixel data".to_string());
+    let bytes_per_sample = if max_value < 256 { 1 } else { 2 };
+    let count = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3))
+        .ok_or_else(truncated)?;
+    let size = count.checked_mul(bytes_per_sample).ok_or_else(truncated)?;
+    if size > data.len() {
+        return Err(truncated());
+    }
+    let samples: Vec<usize> = if magic == "P3" {
+        (0..count)
+            .map(|_| number("sample"))
+            .collect::<io::Result<_>>()?
+    } else {
+        // A single whitespace character separates the header from the samples
+        let start = position + 1;
+        let bytes = data.get(start..start + size).ok_or_else(truncated)?;
+        if bytes_per_sample == 1 {
+            bytes.iter().map(|&byte| byte as usize).collect()
+        } else {
+            bytes
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as usize)
+                .collect()
+        }
+    };
+
+    let scale = 1.0 / max_value as f64;
+    let decode = |value: f64| if srgb { decode_srgb(value) } else { value };
+    let pixels = samples
+        .chunks_exact(3)
+        .map(|sample| {
+            Color::new(
+                decode((sample[0] as f64 * scale).min(1.0)),
+                decode((sample[1] as f64 * scale).min(1.0)),
+                decode((sample[2] as f64 * scale).min(1.0)),
+            )
+        })
+        .collect();
+    image(width, height, pixels)
+}
+
+// Inverse of the sRGB transfer function
+pub fn decode_srgb(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn read_hdr(data: &[u8]) -> io::Result<Image> {
+    let mut position = 0;
+    let mut next_line = || -> io::Result<String> {
+        let end = data[position..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .ok_or_else(|| invalid("truncated Radiance header".to_string()))?;
+        let line = String::from_utf8_lossy(&data[position..position + end]).into_owned();
+        position += end + 1;
+        Ok(line)
+    };
+
+    let magic = next_line()?;
+    if !magic.starts_with("#?") {
+        return Err(invalid("not a Radiance HDR file".to_string()));
+    }
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid(format!("unsupported Radiance format `{}`", format)));
+            }
+        }
+    }
+
+    // Only the standard orientation with rows from the top is supported
+    let resolution = next_line()?;
+    let dimensions: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match dimensions[..] {
+        ["-Y", height, "+X", width] => (height.parse().ok(), width.parse().ok()),
+        _ => (None, None),
+    };
+    let (Some(height), Some(width)) = (height, width) else {
+        return Err(invalid(format!(
+            "unsupported Radiance resolution `{}`",
+            resolution
+        )));
+    };
+    let width: usize = width;
+    let height: usize = height;
+
+    // Smallest encoding of a scanline: flat pixels, or runs of up to 128 bytes per channel
+    // after a 4-byte header. Sizes the file cannot hold are rejected before allocating.
+    let truncated = || invalid("truncated Radiance pixel data".to_string());
+    let smallest_scanline = if (8..=0x7fff).contains(&width) {
+        4 + 4 * 2 * width.div_ceil(128)
+    } else {
+        width.checked_mul(4).ok_or_else(truncated)?
+    };
+    let smallest = height
+        .checked_mul(smallest_scanline)
+        .ok_or_else(truncated)?;
+    if smallest > data.len() - position {
+        return Err(truncated());
+    }
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        let header = data.get(position..position + 4).ok_or_else(truncated)?;
+        let run_length_encoded = (8..=0x7fff).contains(&width)
+            && header[0] == 2
+            && header[1] == 2
+            && header[2] & 0x80 == 0;
+
+        if run_length_encoded {
+            if ((header[2] as usize) << 8 | header[3] as usize) != width {
+                return Err(invalid("Radiance scanline width mismatch".to_string()));
+            }
+            position += 4;
+            for channel in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = *data.get(position).ok_or_else(truncated)? as usize;
+                    position += 1;
+                    if count > 128 {
+                        let count = count - 128;
+                        let value = *data.get(position).ok_or_else(truncated)?;
+                        position += 1;
+                        if x + count > width {
+                            return Err(invalid("Radiance run exceeds scanline".to_string()));
+                        }
+                        for pixel in &mut scanline[x..x + count] {
+                            pixel[channel] = value;
+                        }
+                        x += count;
+                    } else {
+                        if count == 0 || x + count > width {
+                            return Err(invalid("invalid Radiance run".to_string()));
+                        }
+                        let values = data.get(position..position + count).ok_or_else(truncated)?;
+                        for (pixel, &value) in scanline[x..x + count].iter_mut().zip(values) {
+                            pixel[channel] = value;
+                        }
+                        position += count;
+                        x += count;
+                    }
+                }
+            }
+        } else {
+            let values = data
+                .get(position..position + width * 4)
+                .ok_or_else(truncated)?;
+            for (pixel, bytes) in scanline.iter_mut().zip(values.chunks_exact(4)) {
+                pixel.copy_from_slice(bytes);
+            }
+            position += width * 4;
+        }
+
+        pixels.extend(scanline.iter().map(|&rgbe| decode_rgbe(rgbe)));
+    }
+
+    image(width, height, pixels)
+}
+
+pub fn decode_rgbe(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+    let scale = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+    Color::new(
+        (rgbe[0] as f64 + 0.5) * scale,
+        (rgbe[1] as f64 + 0.5) * scale,
+        (rgbe[2] as f64 + 0.5) * scale,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `data` to a file of its own with the given extension and reads it back
+    fn read_file(name: &str, data: &[u8]) -> io::Result<Image> {
+        let path = std::env::temp_dir().join(format!(
+            "rust_raytracer_input_{}_{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, data).unwrap();
+        let image = read(&path);
+        fs::remove_file(&path).unwrap();
+        image
+    }
+
+    #[test]
+    fn reads_small_images() {
+        let image = read_file("small.ppm", b"P3\n2 1\n255\n255 0 0  0 0 255\n").unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        let image = read_ppm(b"P6 1 1 255\n\x00\xff\x00", false).unwrap();
+        assert_eq!(image.pixel(0, 0).y(), 1.0);
+        let image = read_hdr(
+            b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n\x80\x80\x80\x81\x00\x00\x00\x00",
+        )
+        .unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+    }
+
+    #[test]
+    fn empty_images_are_rejected() {
+        for (name, data) in [
+            ("empty.ppm", &b"P6\n0 0\n255\n"[..]),
+            ("empty_row.ppm", &b"P3\n4 0\n255\n"[..]),
+            ("empty.hdr", &b"#?RADIANCE\n\n-Y 0 +X 0\n"[..]),
+        ] {
+            let error = read_file(name, data).err().unwrap();
+            assert!(error.to_string().starts_with("empty image"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn sizes_beyond_the_data_are_rejected() {
+        for data in [
+            &b"P6\n100000 100000\n255\n\x00\x00\x00"[..],
+            &b"P3\n100000 100000\n255\n0 0 0"[..],
+            &b"P6\n18446744073709551615 2\n65535\n"[..],
+        ] {
+            assert!(read_ppm(data, true).is_err());
+        }
+        for data in [
+            &b"#?RADIANCE\n\n-Y 100000 +X 100000\n\x02\x02\x00\x00"[..],
+            &b"#?RADIANCE\n\n-Y 3 +X 4611686018427387904\n"[..],
+        ] {
+            assert!(read_hdr(data).is_err());
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! A path tracer based on *Ray Tracing in One Weekend*.
+//!
+//! Scenes are either loaded from a TOML description with [`scene::load`] or assembled from
+//! the primitives and materials in this crate, and rendered with [`render::Renderer`].
+pub mod bvh;
+pub mod camera;
+pub mod environment;
+pub mod hit;
+pub mod image;
+pub mod input;
+pub mod light;
+pub mod material;
+pub mod microfacet;
+pub mod obj;
+pub mod output;
+pub mod random;
+pub mod ray;
+pub mod render;
+pub mod sampling;
+pub mod scene;
+pub mod spectrum;
+pub mod sphere;
+pub mod texture;
+pub mod tlas;
+pub mod transform;
+pub mod triangle;
+pub mod vec;
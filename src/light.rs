@@ -0,0 +1,263 @@
+// light.rs
+use super::random::random;
+use super::sampling::tangent_frame;
+use super::vec::{Color, Point3, Vec3};
+
+use std::f64::consts::PI;
+
+// Light that is not part of the world, so it can only be reached through shadow rays
+pub trait Light: Send + Sync {
+    // Illumination arriving at `point`, None when the point is outside the light's reach
+    fn sample(&self, point: Point3) -> Option<LightSample>;
+}
+
+pub struct LightSample {
+    // Unit vector from the lit point towards the light
+    pub direction: Vec3,
+    // Distance to the light along `direction`, infinite for directional lights
+    pub distance: f64,
+    // Irradiance on a surface facing the light. For lights with an extent, this is the
+    // radiance from `direction` divided by the density of having picked it.
+    pub irradiance: Color,
+}
+
+// How the intensity of point and spot lights decreases with the distance `d`
+#[derive(Clone, Copy)]
+pub enum Falloff {
+    // Constant, a stylized light that reaches everything equally
+    None,
+    // 1/d
+    Linear,
+    // 1/d², the physically correct falloff
+    Quadratic,
+}
+
+impl Falloff {
+    fn attenuation(self, distance: f64) -> f64 {
+        match self {
+            Falloff::None => 1.0,
+            Falloff::Linear => 1.0 / distance,
+            Falloff::Quadratic => 1.0 / (distance * distance),
+        }
+    }
+}
+
+pub struct PointLight {
+    position: Point3,
+    intensity: Color,
+    falloff: Falloff,
+}
+
+impl PointLight {
+    // `intensity` is the radiant intensity, i.e. power per solid angle
+    pub fn new(position: Point3, intensity: Color, falloff: Falloff) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+            falloff,
+        }
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, point: Point3) -> Option<LightSample> {
+        let offset = self.position - point;
+        let distance = offset.length();
+        if distance <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: offset / distance,
+            distance,
+            irradiance: self.falloff.attenuation(distance) * self.intensity,
+        })
+    }
+}
+
+// Point light restricted to a cone, fading out between the inner and outer angle
+pub struct SpotLight {
+    light: PointLight,
+    direction: Vec3,
+    cos_inner: f64,
+    cos_outer: f64,
+}
+
+impl SpotLight {
+    // The cone angles in degrees are measured from `direction`, the axis the light shines along
+    pub fn new(
+        position: Point3,
+        direction: Vec3,
+        intensity: Color,
+        falloff: Falloff,
+        inner_angle: f64,
+        outer_angle: f64,
+    ) -> SpotLight {
+        SpotLight {
+            light: PointLight::new(position, intensity, falloff),
+            direction: direction.normalized(),
+            cos_inner: inner_angle.to_radians().cos(),
+            cos_outer: outer_angle.to_radians().cos(),
+        }
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, point: Point3) -> Option<LightSample> {
+        let mut sample = self.light.sample(point)?;
+        let cosine = self.direction.dot((-1.0) * sample.direction);
+        if cosine <= self.cos_outer {
+            return None;
+        }
+        if cosine < self.cos_inner {
+            let t = (cosine - self.cos_outer) / (self.cos_inner - self.cos_outer);
+            sample.irradiance *= t * t * (3.0 - 2.0 * t);
+        }
+        Some(sample)
+    }
+}
+
+// Light from infinitely far away, like the sun. A non-zero angular diameter spreads the
+// light over a small disk of directions, which softens the shadows.
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Color,
+    cos_theta_max: f64,
+}
+
+impl DirectionalLight {
+    // `direction` is the direction the light travels in, the angular diameter is in degrees
+    pub fn new(direction: Vec3, irradiance: Color, angular_diameter: f64) -> DirectionalLight {
+        DirectionalLight {
+            direction: direction.normalized(),
+            irradiance,
+            cos_theta_max: (0.5 * angular_diameter).to_radians().cos(),
+        }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _point: Point3) -> Option<LightSample> {
+        let w = (-1.0) * self.direction;
+        let direction = if self.cos_theta_max < 1.0 {
+            // Uniform over the cone of the disk; the radiance of the disk is the irradiance
+            // over its solid angle, so dividing by the density leaves the irradiance
+            let phi = 2.0 * PI * random();
+            let z = 1.0 + random() * (self.cos_theta_max - 1.0);
+            let sin_theta = (1.0 - z * z).sqrt();
+            let (u, v) = tangent_frame(w);
+            phi.cos() * sin_theta * u + phi.sin() * sin_theta * v + z * w
+        } else {
+            w
+        };
+        Some(LightSample {
+            direction,
+            distance: f64::INFINITY,
+            irradiance: self.irradiance,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+
+    fn white() -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
+    // Irradiance from `light` at `point`, zero where it does not reach
+    fn irradiance(light: &dyn Light, point: Point3) -> f64 {
+        light
+            .sample(point)
+            .map_or(0.0, |sample| sample.irradiance.x())
+    }
+
+    #[test]
+    fn point_lights_fall_off_with_distance() {
+        let origin = Point3::new(0.0, 0.0, 0.0);
+        let at = |falloff, distance| {
+            let light = PointLight::new(Point3::new(0.0, distance, 0.0), 8.0 * white(), falloff);
+            irradiance(&light, origin)
+        };
+        for distance in [0.5, 1.0, 3.0] {
+            assert!((at(Falloff::Quadratic, distance) * distance * distance - 8.0).abs() < 1e-12);
+            assert!((at(Falloff::Linear, distance) * distance - 8.0).abs() < 1e-12);
+            assert_eq!(at(Falloff::None, distance), 8.0);
+        }
+
+        let light = PointLight::new(Point3::new(3.0, 4.0, 0.0), white(), Falloff::Quadratic);
+        let sample = light.sample(origin).unwrap();
+        assert!((sample.direction - Vec3::new(0.6, 0.8, 0.0)).length() < 1e-12);
+        assert_eq!(sample.distance, 5.0);
+        assert!(light.sample(Point3::new(3.0, 4.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn spot_lights_fade_between_their_angles() {
+        // Shining straight down from one unit above the origin
+        let spot = SpotLight::new(
+            Point3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            white(),
+            Falloff::None,
+            20.0,
+            40.0,
+        );
+        let at_angle =
+            |degrees: f64| irradiance(&spot, Point3::new(degrees.to_radians().tan(), 0.0, 0.0));
+        assert_eq!(at_angle(0.0), 1.0);
+        assert_eq!(at_angle(19.9), 1.0);
+        assert_eq!(at_angle(40.0), 0.0);
+        assert_eq!(at_angle(60.0), 0.0);
+        // Points above the light are behind it
+        assert_eq!(irradiance(&spot, Point3::new(0.0, 2.0, 0.0)), 0.0);
+
+        let (cos_inner, cos_outer) = (20f64.to_radians().cos(), 40f64.to_radians().cos());
+        let mut previous = 1.0;
+        for degrees in [22.0f64, 26.0, 30.0, 34.0, 38.0] {
+            let t = (degrees.to_radians().cos() - cos_outer) / (cos_inner - cos_outer);
+            let expected = t * t * (3.0 - 2.0 * t);
+            let value = at_angle(degrees);
+            assert!((value - expected).abs() < 1e-9, "{} at {}°", value, degrees);
+            assert!(value < previous);
+            previous = value;
+        }
+    }
+
+    #[test]
+    fn directional_lights_sample_their_disk_uniformly() {
+        let direction = Vec3::new(1.0, -2.0, 0.5);
+        let towards = (-1.0) * direction.normalized();
+        let sharp = DirectionalLight::new(direction, 3.0 * white(), 0.0);
+        let sample = sharp.sample(Point3::new(5.0, 1.0, 2.0)).unwrap();
+        assert!((sample.direction - towards).length() < 1e-12);
+        assert_eq!(sample.distance, f64::INFINITY);
+
+        // Uniform over the solid angle of the cone, the cosine to its axis is uniform
+        // between cos θmax and 1
+        random::seed(6);
+        let soft = DirectionalLight::new(direction, 3.0 * white(), 10.0);
+        let cos_theta_max = 5f64.to_radians().cos();
+        let samples = 100_000;
+        let (mut mean, mut square) = (0.0, 0.0);
+        for _ in 0..samples {
+            let sample = soft.sample(Point3::new(0.0, 0.0, 0.0)).unwrap();
+            assert!((sample.direction.length() - 1.0).abs() < 1e-12);
+            assert_eq!(sample.irradiance.x(), 3.0);
+            let cosine = sample.direction.dot(towards);
+            assert!(cosine >= cos_theta_max - 1e-12);
+            // Position within [cos θmax, 1], which should be uniform in [0, 1]
+            let t = (cosine - cos_theta_max) / (1.0 - cos_theta_max);
+            mean += t;
+            square += t * t;
+        }
+        let (mean, square) = (mean / samples as f64, square / samples as f64);
+        assert!((mean - 0.5).abs() < 0.01, "mean {}", mean);
+        assert!(
+            (square - 1.0 / 3.0).abs() < 0.01,
+            "second moment {}",
+            square
+        );
+    }
+}
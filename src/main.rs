@@ -1,158 +1,72 @@
 // main.rs
 use std::{
-    fs::File,
+    env,
     io::{stderr, Write},
+    process,
     sync::Arc,
 };
 
-mod bvh;
-mod camera;
-mod hit;
-mod material;
-mod ray;
-mod sphere;
-mod vec;
+mod cli;
 
-use bvh::BvhNode;
-use camera::Camera;
-use hit::{Hit, World};
-use material::{Dielectric, Lambertian, Metal};
-use rand::Rng;
-use ray::Ray;
-use sphere::Sphere;
-use vec::{Color, Point3, Vec3};
+use rust_raytracer::output;
+use rust_raytracer::render::Renderer;
+use rust_raytracer::scene;
 
-fn ray_color(ray: &Ray, world: &World, depth: u64) -> Color {
-    if depth == 0 {
-        return Color::new(0.0, 0.0, 0.0);
-    }
-
-    if let Some(record) = world.hit(ray, 0.001, f64::INFINITY) {
-        if let Some((attenuation, scattered)) = record.material.scatter(ray, &record) {
-            attenuation * ray_color(&scattered, world, depth - 1)
-        } else {
-            Color::new(0.0, 0.0, 0.0)
+fn main() {
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(cli::Command::Render(options)) => options,
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
         }
-    } else {
-        let unit_direction = ray.direction().normalized();
-        let time = 0.5 * (unit_direction.y() + 1.0);
-        (1.0 - time) * Color::new(1.0, 1.0, 1.0) + time * Color::new(0.5, 0.7, 1.0)
-    }
-}
-
-fn default_scene() -> World {
-    let mut rng = rand::thread_rng();
-    let mut objects: Vec<Arc<dyn Hit>> = Vec::new();
-
-    let ground_mat = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
-    let ground_sphere = Sphere::new(Point3::new(0.0, -1000.0, 0.0), 1000.0, ground_mat);
-
-    objects.push(Arc::new(ground_sphere));
-
-    for a in -11..=11 {
-        for b in -11..=11 {
-            let choose_mat: f64 = rng.gen();
-            let center = Point3::new(
-                (a as f64) + rng.gen_range(0.0..0.9),
-                0.2,
-                (b as f64) + rng.gen_range(0.0..0.9),
-            );
-
-            if choose_mat < 0.8 {
-                // Diffuse
-                let albedo = Color::random(0.0..1.0) * Color::random(0.0..1.0);
-                let sphere_mat = Arc::new(Lambertian::new(albedo));
-                let sphere = Sphere::new(center, 0.2, sphere_mat);
-
-                objects.push(Arc::new(sphere));
-            } else if choose_mat < 0.95 {
-                // Metal
-                let albedo = Color::random(0.4..1.0);
-                let fuzz = rng.gen_range(0.0..0.5);
-                let sphere_mat = Arc::new(Metal::new(albedo, fuzz));
-                let sphere = Sphere::new(center, 0.2, sphere_mat);
-
-                objects.push(Arc::new(sphere));
-            } else {
-                // Glass
-                let sphere_mat = Arc::new(Dielectric::new(1.5));
-                let sphere = Sphere::new(center, 0.2, sphere_mat);
-
-                objects.push(Arc::new(sphere));
-            }
+        Err(error) => {
+            eprintln!("error: {}\n\n{}", error, cli::USAGE);
+            process::exit(2);
         }
-    }
-
-    let mat1 = Arc::new(Dielectric::new(1.5));
-    let mat2 = Arc::new(Lambertian::new(Color::new(0.4, 0.2, 0.1)));
-    let mat3 = Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0));
-
-    let sphere1 = Sphere::new(Point3::new(0.0, 1.0, 0.0), 1.0, mat1);
-    let sphere2 = Sphere::new(Point3::new(-4.0, 1.0, 0.0), 1.0, mat2);
-    let sphere3 = Sphere::new(Point3::new(4.0, 1.0, 0.0), 1.0, mat3);
-
-    objects.push(Arc::new(sphere1));
-    objects.push(Arc::new(sphere2));
-    objects.push(Arc::new(sphere3));
-    let bvh = BvhNode::new(objects);
-
-    let mut world = World::new();
-    world.push(Box::new(bvh));
-    world
-}
-
-fn main() {
-    // Image
-    const ASPECT_RATIO: f64 = 3.0 / 2.0;
-    const IMAGE_WIDTH: u64 = 1200;
-    const IMAGE_HEIGHT: u64 = ((IMAGE_WIDTH as f64) / ASPECT_RATIO) as u64;
-    const SAMPLES_PER_PIXEL: u64 = 500;
-    const MAX_DEPTH: u64 = 50;
-    const OUTPUT_FILENAME: &str = "image.ppm";
-    // World
-    let world = default_scene();
-
-    // Camera
-    let lookfrom = Point3::new(13.0, 2.0, 3.0);
-    let lookat = Point3::new(0.0, 0.0, 0.0);
-    let vup = Vec3::new(0.0, 1.0, 0.0);
-    let dist_to_focus = 10.0;
-    let aperture = 0.1;
-
-    let camera = Camera::new(
-        lookfrom,
-        lookat,
-        vup,
-        20.0,
-        ASPECT_RATIO,
-        aperture,
-        dist_to_focus,
-    );
-
-    let mut file = File::create(OUTPUT_FILENAME).expect("Failed to create file");
-    write!(file, "P3\n{} {}\n255\n", IMAGE_WIDTH, IMAGE_HEIGHT).expect("Failed to write to file");
-
-    let mut rng = rand::thread_rng();
-    for j in (0..IMAGE_HEIGHT).rev() {
-        eprint!("\rScanlines remaining: {:3}", IMAGE_HEIGHT - j - 1);
-        stderr().flush().unwrap();
-
-        for i in 0..IMAGE_WIDTH {
-            let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-            for _ in 0..SAMPLES_PER_PIXEL {
-                let random_u: f64 = rng.gen();
-                let random_v: f64 = rng.gen();
-
-                let u = ((i as f64) + random_u) / ((IMAGE_WIDTH - 1) as f64);
-                let v = ((j as f64) + random_v) / ((IMAGE_HEIGHT - 1) as f64);
-
-                let ray = camera.get_ray(u, v);
-                pixel_color += ray_color(&ray, &world, MAX_DEPTH);
-            }
-            writeln!(file, "{}", pixel_color.format_color(SAMPLES_PER_PIXEL))
-                .expect("Failed to write to file");
+    };
+
+    // Scene
+    let mut scene = match scene::load(&options.scene) {
+        Ok(scene) => scene,
+        Err(error) => {
+            eprintln!("Failed to load scene: {}", error);
+            process::exit(1);
+        }
+    };
+    let aspect_ratio = match options.apply(&mut scene.settings) {
+        Ok(aspect_ratio) => aspect_ratio,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(2);
+        }
+    };
+    let camera = scene.camera.with_aspect_ratio(aspect_ratio);
+    let renderer = match Renderer::new(camera, Arc::new(scene.world), scene.settings) {
+        Ok(renderer) => renderer,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            process::exit(2);
         }
+    };
+    let renderer = renderer
+        .with_environment(scene.environment)
+        .with_lights(scene.lights)
+        .with_punctual_lights(scene.punctual_lights)
+        .on_progress(|finished, total| {
+            eprint!("\rTiles remaining: {:5}", total - finished);
+            stderr().flush().unwrap();
+        });
+
+    // Render
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .build()
+        .expect("Failed to create thread pool");
+    let image = pool.install(|| renderer.render());
+
+    if let Err(error) = output::write(&image, &options.output, options.format) {
+        eprintln!("\nFailed to write {}: {}", options.output.display(), error);
+        process::exit(1);
     }
-    file.flush().expect("Failed to flush file");
-    eprintln!("Done.");
+    eprintln!("\nDone.");
 }
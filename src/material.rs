@@ -1,19 +1,54 @@
 //material.rs
 use super::hit::HitRecord;
+use super::microfacet::{reflect, schlick, schlick_weight, Ggx};
+use super::random::random;
 use super::ray::Ray;
+use super::sampling::{cosine_hemisphere, tangent_frame, Frame};
+use super::spectrum::RefractiveIndex;
+use super::texture::{SolidColor, Texture};
 use super::vec::{Color, Vec3};
 
-use rand::Rng;
+use std::f64::consts::PI;
+use std::sync::Arc;
 
 pub trait Scatter: Send + Sync {
+    // Picks an outgoing ray, weighted by the BSDF times the cosine over the density of the pick
     fn scatter(&self, ray_in: &Ray, record: &HitRecord) -> Option<(Color, Ray)>;
+
+    // BSDF times the cosine for light arriving from `direction`, used for directions that
+    // were picked by sampling the lights instead of by `scatter`
+    fn eval(&self, _ray_in: &Ray, _record: &HitRecord, _direction: Vec3) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    // Solid angle density with which `scatter` picks `direction`. Zero marks materials whose
+    // scattering cannot be evaluated, like perfect mirrors, which are never light sampled.
+    // When it is positive, the weight returned by `scatter` must equal `eval / pdf`.
+    fn pdf(&self, _ray_in: &Ray, _record: &HitRecord, _direction: Vec3) -> f64 {
+        0.0
+    }
+
+    // Radiance emitted by the surface towards the incoming ray
+    fn emitted(&self, _ray_in: &Ray, _record: &HitRecord) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    // Whether `scatter` picks directions that depend on the ray's wavelength, which spectral
+    // paths can then only follow for their hero wavelength
+    fn is_dispersive(&self) -> bool {
+        false
+    }
 }
 pub struct Lambertian {
-    albedo: Color,
+    albedo: Arc<dyn Texture>,
 }
 
 impl Lambertian {
     pub fn new(albedo: Color) -> Lambertian {
+        Lambertian::textured(Arc::new(SolidColor::new(albedo)))
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>) -> Lambertian {
         Lambertian { albedo }
     }
 }
@@ -25,17 +60,33 @@ impl Scatter for Lambertian {
             scatter_direction = record.normal;
         }
         let scattered = Ray::new(record.point, scatter_direction);
-        Some((self.albedo, scattered))
+        let albedo = self.albedo.value(record.u, record.v, record.point);
+        Some((albedo, scattered))
+    }
+
+    fn eval(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> Color {
+        let albedo = self.albedo.value(record.u, record.v, record.point);
+        self.pdf(ray_in, record, direction) * albedo
+    }
+
+    // Offsetting a random unit vector by the normal gives a cosine-weighted hemisphere
+    fn pdf(&self, _ray_in: &Ray, record: &HitRecord, direction: Vec3) -> f64 {
+        let cosine = record.normal.dot(direction.normalized());
+        cosine.max(0.0) / PI
     }
 }
 
 pub struct Metal {
-    albedo: Color,
+    albedo: Arc<dyn Texture>,
     fuzz: f64,
 }
 
 impl Metal {
     pub fn new(albedo: Color, fuzz: f64) -> Metal {
+        Metal::textured(Arc::new(SolidColor::new(albedo)), fuzz)
+    }
+
+    pub fn textured(albedo: Arc<dyn Texture>, fuzz: f64) -> Metal {
         Metal { albedo, fuzz }
     }
 }
@@ -48,46 +99,940 @@ impl Scatter for Metal {
             reflected + self.fuzz * Vec3::random_in_unit_sphere(),
         );
         if scattered.direction().dot(record.normal) > 0.0 {
-            Some((self.albedo, scattered))
+            let albedo = self.albedo.value(record.u, record.v, record.point);
+            Some((albedo, scattered))
         } else {
             None
         }
     }
 }
 
+// Metal/roughness surface: a GGX specular lobe over a diffuse base that fades out as the
+// surface becomes metallic, when the base color tints the reflection instead
+pub struct Microfacet {
+    base_color: Arc<dyn Texture>,
+    distribution: Ggx,
+    metallic: f64,
+}
+
+impl Microfacet {
+    // Reflectance of dielectrics at normal incidence, about 4% for a refractive index of 1.5
+    const DIELECTRIC_F0: f64 = 0.04;
+    // Smallest chance of sampling the specular lobe when there is a diffuse lobe as well
+    const MIN_SPECULAR_PROBABILITY: f64 = 0.25;
+
+    pub fn new(base_color: Color, roughness: f64, metallic: f64) -> Microfacet {
+        Microfacet::textured(Arc::new(SolidColor::new(base_color)), roughness, metallic)
+    }
+
+    pub fn textured(base_color: Arc<dyn Texture>, roughness: f64, metallic: f64) -> Microfacet {
+        Microfacet {
+            base_color,
+            distribution: Ggx::new(roughness),
+            metallic,
+        }
+    }
+
+    // Base color, reflectance at normal incidence and the chance of sampling the specular lobe
+    fn lobes(&self, record: &HitRecord, wo: Vec3) -> (Color, Color, f64) {
+        let base_color = self.base_color.value(record.u, record.v, record.point);
+        let dielectric = Color::new(
+            Self::DIELECTRIC_F0,
+            Self::DIELECTRIC_F0,
+            Self::DIELECTRIC_F0,
+        );
+        let f0 = (1.0 - self.metallic) * dielectric + self.metallic * base_color;
+
+        let specular = schlick(f0, wo.z()).luminance();
+        let diffuse = (1.0 - self.metallic) * base_color.luminance() * (1.0 - specular);
+        let probability = if diffuse > 0.0 {
+            (specular / (specular + diffuse)).clamp(Self::MIN_SPECULAR_PROBABILITY, 1.0)
+        } else {
+            1.0
+        };
+        (base_color, f0, probability)
+    }
+
+    // BSDF times the cosine, in the local frame of the shading normal
+    fn eval_local(&self, base_color: Color, f0: Color, wo: Vec3, wi: Vec3) -> Color {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let half = (wo + wi).normalized();
+        let fresnel = schlick(f0, wo.dot(half));
+        let specular = self.distribution.d(half) * self.distribution.g(wo, wi) / (4.0 * wo.z());
+        // The diffuse base gets what the specular layer lets through at the viewing angle. Fresnel
+        // at the half vector would vary with `wi` and let the two lobes gain energy together.
+        let transmitted = Color::new(1.0, 1.0, 1.0) - schlick(f0, wo.z());
+        let diffuse = (1.0 - self.metallic) * wi.z() / PI;
+        specular * fresnel + diffuse * transmitted * base_color
+    }
+
+    fn pdf_local(&self, wo: Vec3, wi: Vec3, specular_probability: f64) -> f64 {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+        let specular = self.distribution.reflection_pdf(wo, wi);
+        let diffuse = wi.z() / PI;
+        specular_probability * specular + (1.0 - specular_probability) * diffuse
+    }
+}
+
+impl Scatter for Microfacet {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord) -> Option<(Color, Ray)> {
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let (base_color, f0, specular_probability) = self.lobes(record, wo);
+
+        let wi = if random() < specular_probability {
+            let micro_normal = self
+                .distribution
+                .sample_visible_normal(wo, random(), random());
+            reflect(wo, micro_normal)
+        } else {
+            cosine_hemisphere(random(), random())
+        };
+        let pdf = self.pdf_local(wo, wi, specular_probability);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        let weight = self.eval_local(base_color, f0, wo, wi) / pdf;
+        Some((weight, Ray::new(record.point, frame.to_world(wi))))
+    }
+
+    fn eval(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> Color {
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        let wi = frame.to_local(direction.normalized());
+        let (base_color, f0, _) = self.lobes(record, wo);
+        self.eval_local(base_color, f0, wo, wi)
+    }
+
+    fn pdf(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> f64 {
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        let wi = frame.to_local(direction.normalized());
+        let (_, _, specular_probability) = self.lobes(record, wo);
+        self.pdf_local(wo, wi, specular_probability)
+    }
+}
+
+// Disney-style uber-material. A clear coat sits on top of a base that blends a metal, a
+// dielectric with a diffuse body and sheen, and a rough transmissive dielectric. Every
+// parameter except the index of refraction is in [0, 1].
+pub struct Principled {
+    base_color: Arc<dyn Texture>,
+    metallic: f64,
+    distribution: Ggx,
+    specular: f64,
+    specular_tint: f64,
+    sheen: f64,
+    clearcoat: f64,
+    clearcoat_distribution: Ggx,
+    transmission: f64,
+    ior: f64,
+}
+
+// Everything about a hit that depends on the outgoing direction but not on the incoming one
+struct PrincipledLobes {
+    base_color: Color,
+    // Reflectance at normal incidence of the opaque dielectric
+    dielectric_f0: Color,
+    // Fraction of the light reflected by the clear coat, which the base never receives
+    coat: f64,
+    // Refractive index below the surface over the one above
+    eta: f64,
+    // Chance of sampling the coat, specular, diffuse and transmission lobe
+    probabilities: [f64; 4],
+}
+
+impl Principled {
+    // Upper bound on the directional albedo of the sheen lobe, which is taken out of the
+    // diffuse lobe so the two together never reflect more than the diffuse lobe alone
+    const SHEEN_ALBEDO: f64 = 0.1;
+    const CLEARCOAT_F0: f64 = 0.04;
+
+    pub fn new(base_color: Color) -> Principled {
+        Principled::textured(Arc::new(SolidColor::new(base_color)))
+    }
+
+    pub fn textured(base_color: Arc<dyn Texture>) -> Principled {
+        Principled {
+            base_color,
+            metallic: 0.0,
+            distribution: Ggx::new(0.5),
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            clearcoat: 0.0,
+            clearcoat_distribution: Ggx::new(0.0),
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+
+    pub fn with_metallic(mut self, metallic: f64) -> Principled {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn with_roughness(mut self, roughness: f64) -> Principled {
+        self.distribution = Ggx::new(roughness);
+        self
+    }
+
+    // `specular` scales the dielectric reflectance at normal incidence from 0 to 8%, with the
+    // default of 0.5 matching an index of refraction of 1.5. `tint` blends it towards the hue
+    // of the base color.
+    pub fn with_specular(mut self, specular: f64, tint: f64) -> Principled {
+        self.specular = specular;
+        self.specular_tint = tint;
+        self
+    }
+
+    // Soft grazing reflection on top of the diffuse lobe, as on cloth
+    pub fn with_sheen(mut self, sheen: f64) -> Principled {
+        self.sheen = sheen;
+        self
+    }
+
+    // A second, colorless specular layer, like the lacquer on car paint. The gloss goes from
+    // a hazy coat at 0 to a mirror-like one at 1.
+    pub fn with_clearcoat(mut self, clearcoat: f64, gloss: f64) -> Principled {
+        self.clearcoat = clearcoat;
+        self.clearcoat_distribution = Ggx::new(0.3 * (1.0 - gloss));
+        self
+    }
+
+    // Fraction of the dielectric that lets light through instead of scattering it diffusely.
+    // The light is refracted with the roughness of the surface and tinted by the base color.
+    pub fn with_transmission(mut self, transmission: f64, ior: f64) -> Principled {
+        self.transmission = transmission;
+        self.ior = ior;
+        self
+    }
+
+    fn lobes(&self, record: &HitRecord, wo: Vec3) -> PrincipledLobes {
+        let base_color = self.base_color.value(record.u, record.v, record.point);
+        let white = Color::new(1.0, 1.0, 1.0);
+        let luminance = base_color.luminance();
+        let tint = if luminance > 0.0 {
+            base_color / luminance
+        } else {
+            white
+        };
+        let dielectric_f0 =
+            0.08 * self.specular * ((1.0 - self.specular_tint) * white + self.specular_tint * tint);
+        let coat = self.clearcoat * Self::clearcoat_fresnel(wo.z());
+        let eta = if record.front_face {
+            self.ior
+        } else {
+            1.0 / self.ior
+        };
+
+        // Weigh the lobes by a cheap estimate of how much light each of them reflects
+        let (opaque, transmissive) = self.dielectric_weights();
+        let base = 1.0 - coat;
+        let dielectric_reflectance = schlick(dielectric_f0, wo.z()).luminance();
+        let weights = [
+            coat,
+            base * (self.metallic * schlick(base_color, wo.z()).luminance()
+                + opaque * dielectric_reflectance),
+            base * opaque
+                * (1.0 - dielectric_reflectance)
+                * ((1.0 - self.sheen * Self::SHEEN_ALBEDO) * luminance
+                    + self.sheen * Self::SHEEN_ALBEDO),
+            base * transmissive,
+        ];
+        let total: f64 = weights.iter().sum();
+        let probabilities = if total > 0.0 {
+            weights.map(|weight| weight / total)
+        } else {
+            [0.0; 4]
+        };
+
+        PrincipledLobes {
+            base_color,
+            dielectric_f0,
+            coat,
+            eta,
+            probabilities,
+        }
+    }
+
+    // Share of the opaque and of the transmissive dielectric in the base layer
+    fn dielectric_weights(&self) -> (f64, f64) {
+        let dielectric = 1.0 - self.metallic;
+        (
+            dielectric * (1.0 - self.transmission),
+            dielectric * self.transmission,
+        )
+    }
+
+    fn clearcoat_fresnel(cosine: f64) -> f64 {
+        Self::CLEARCOAT_F0 + (1.0 - Self::CLEARCOAT_F0) * schlick_weight(cosine)
+    }
+
+    // BSDF times the cosine, in the local frame of the shading normal
+    fn eval_local(&self, lobes: &PrincipledLobes, wo: Vec3, wi: Vec3) -> Color {
+        let white = Color::new(1.0, 1.0, 1.0);
+        let mut value = Color::new(0.0, 0.0, 0.0);
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return value;
+        }
+        let (opaque, transmissive) = self.dielectric_weights();
+        let base = 1.0 - lobes.coat;
+
+        if transmissive > 0.0 {
+            let dielectric = self.distribution.eval_dielectric(wo, wi, lobes.eta);
+            // Tinted once on the way in and once on the way out
+            let tint = if wi.z() < 0.0 {
+                Color::new(
+                    lobes.base_color.x().sqrt(),
+                    lobes.base_color.y().sqrt(),
+                    lobes.base_color.z().sqrt(),
+                )
+            } else {
+                white
+            };
+            value += base * transmissive * dielectric * tint;
+        }
+        if wi.z() < 0.0 {
+            return value;
+        }
+
+        let half = (wo + wi).normalized();
+        let cosine = wo.dot(half);
+        if self.clearcoat > 0.0 {
+            let distribution = &self.clearcoat_distribution;
+            let coat = distribution.d(half) * distribution.g(wo, wi) / (4.0 * wo.z());
+            value += self.clearcoat * coat * Self::clearcoat_fresnel(cosine) * white;
+        }
+
+        let specular = self.distribution.d(half) * self.distribution.g(wo, wi) / (4.0 * wo.z());
+        let fresnel = self.metallic * schlick(lobes.base_color, cosine)
+            + opaque * schlick(lobes.dielectric_f0, cosine);
+        value += base * specular * fresnel;
+
+        if opaque > 0.0 {
+            // What the specular layer lets through at this angle reaches the diffuse body
+            let transmitted = white - schlick(lobes.dielectric_f0, wo.z());
+            let sheen = self.sheen * schlick_weight(wi.dot(half));
+            let diffuse =
+                (1.0 - self.sheen * Self::SHEEN_ALBEDO) / PI * lobes.base_color + sheen * white;
+            value += base * opaque * wi.z() * transmitted * diffuse;
+        }
+        value
+    }
+
+    fn pdf_local(&self, lobes: &PrincipledLobes, wo: Vec3, wi: Vec3) -> f64 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+        let [coat, specular, diffuse, transmission] = lobes.probabilities;
+        let mut pdf = transmission * self.distribution.pdf_dielectric(wo, wi, lobes.eta);
+        if wi.z() > 0.0 {
+            pdf += coat * self.clearcoat_distribution.reflection_pdf(wo, wi)
+                + specular * self.distribution.reflection_pdf(wo, wi)
+                + diffuse * wi.z() / PI;
+        }
+        pdf
+    }
+}
+
+impl Scatter for Principled {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord) -> Option<(Color, Ray)> {
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        if wo.z() <= 0.0 {
+            return None;
+        }
+        let lobes = self.lobes(record, wo);
+
+        let [coat, specular, diffuse, _] = lobes.probabilities;
+        let u = random();
+        let wi = if u < coat {
+            let normal = self
+                .clearcoat_distribution
+                .sample_visible_normal(wo, random(), random());
+            reflect(wo, normal)
+        } else if u < coat + specular {
+            let normal = self
+                .distribution
+                .sample_visible_normal(wo, random(), random());
+            reflect(wo, normal)
+        } else if u < coat + specular + diffuse {
+            cosine_hemisphere(random(), random())
+        } else {
+            self.distribution
+                .sample_dielectric(wo, lobes.eta, random(), random(), random())
+        };
+        let pdf = self.pdf_local(&lobes, wo, wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        let weight = self.eval_local(&lobes, wo, wi) / pdf;
+        Some((weight, Ray::new(record.point, frame.to_world(wi))))
+    }
+
+    fn eval(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> Color {
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        let wi = frame.to_local(direction.normalized());
+        self.eval_local(&self.lobes(record, wo), wo, wi)
+    }
+
+    fn pdf(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> f64 {
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        let wi = frame.to_local(direction.normalized());
+        self.pdf_local(&self.lobes(record, wo), wo, wi)
+    }
+}
+
+// Glass and other clear dielectrics. Rough glass scatters through a GGX microfacet interface
+// and can be light sampled; perfectly smooth glass only reflects and refracts exactly.
 pub struct Dielectric {
-    refraction_index: f64,
+    refraction_index: RefractiveIndex,
+    // None for a perfectly smooth surface
+    distribution: Option<Ggx>,
+    // Beer-Lambert absorption coefficient per unit length inside the medium
+    absorption: Color,
 }
 
 impl Dielectric {
     pub fn new(refraction_index: f64) -> Dielectric {
-        Dielectric { refraction_index }
+        Dielectric::dispersive(RefractiveIndex::Constant(refraction_index))
     }
+
+    // Glass whose index changes with the wavelength, which splits white light into colors
+    // when rendering spectrally
+    pub fn dispersive(refraction_index: RefractiveIndex) -> Dielectric {
+        Dielectric {
+            refraction_index,
+            distribution: None,
+            absorption: Color::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    // Frosts the surface; a roughness of zero keeps it perfectly smooth
+    pub fn with_roughness(mut self, roughness: f64) -> Dielectric {
+        self.distribution = (roughness > 0.0).then(|| Ggx::new(roughness));
+        self
+    }
+
+    // Light that travels `distance` inside the medium keeps the fraction `color` of itself,
+    // so thick glass looks more saturated than thin glass
+    pub fn with_absorption(mut self, color: Color, distance: f64) -> Dielectric {
+        let coefficient = |channel: f64| -channel.max(1.0e-6).ln() / distance;
+        self.absorption = Color::new(
+            coefficient(color.x()),
+            coefficient(color.y()),
+            coefficient(color.z()),
+        );
+        self
+    }
+
     fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
         let r0 = ((1.0 - refraction_index) / (1.0 + refraction_index)).powi(2);
         r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
     }
+
+    // Fraction of the light that survives the way from the ray's origin to a hit on the
+    // inside of the surface, where the ray must have traveled through the medium. Paths do not
+    // keep track of the medium they are in, so this assumes closed surfaces that do not
+    // overlap or nest inside other media: a ray that hits the inside started on the surface or
+    // within the medium. Glass inside glass, or liquid in a glass, absorbs as if it were alone.
+    fn transmittance(&self, ray_in: &Ray, record: &HitRecord) -> Color {
+        if record.front_face {
+            return Color::new(1.0, 1.0, 1.0);
+        }
+        let distance = (record.point - ray_in.origin()).length();
+        Color::new(
+            (-self.absorption.x() * distance).exp(),
+            (-self.absorption.y() * distance).exp(),
+            (-self.absorption.z() * distance).exp(),
+        )
+    }
+
+    // Refractive index below the surface over the one above, in the frame of the hit
+    fn eta(&self, ray_in: &Ray, record: &HitRecord) -> f64 {
+        let wavelength = ray_in
+            .wavelength()
+            .unwrap_or(RefractiveIndex::REFERENCE_WAVELENGTH);
+        let mut refraction_index = self.refraction_index.at(wavelength);
+        // Dispersion formulas can give nonsense far from the range they were fitted to; light
+        // then passes as if there were no surface rather than refracting by a negative ratio
+        if !(refraction_index.is_finite() && refraction_index > 0.0) {
+            refraction_index = 1.0;
+        }
+        if record.front_face {
+            refraction_index
+        } else {
+            1.0 / refraction_index
+        }
+    }
 }
+
 impl Scatter for Dielectric {
     fn scatter(&self, ray_in: &Ray, record: &HitRecord) -> Option<(Color, Ray)> {
-        let refraction_ratio = if record.front_face {
-            1.0 / self.refraction_index
-        } else {
-            self.refraction_index
-        };
+        let transmittance = self.transmittance(ray_in, record);
+        if let Some(distribution) = &self.distribution {
+            let frame = Frame::new(record.normal);
+            let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+            if wo.z() <= 0.0 {
+                return None;
+            }
+            let eta = self.eta(ray_in, record);
+            let wi = distribution.sample_dielectric(wo, eta, random(), random(), random());
+            let pdf = distribution.pdf_dielectric(wo, wi, eta);
+            if pdf <= 0.0 {
+                return None;
+            }
+            let weight = distribution.eval_dielectric(wo, wi, eta) / pdf * transmittance;
+            return Some((weight, Ray::new(record.point, frame.to_world(wi))));
+        }
+
+        let refraction_ratio = 1.0 / self.eta(ray_in, record);
         let unit_direction = ray_in.direction().normalized();
         let cos_theta = ((-1.0) * unit_direction).dot(record.normal).abs().min(1.0);
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
-        let mut rng = rand::thread_rng();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
         let will_reflect =
-            cannot_refract || Self::reflectance(cos_theta, refraction_ratio) > rng.gen::<f64>();
+            cannot_refract || Self::reflectance(cos_theta, refraction_ratio) > random();
         let direction = if will_reflect {
             unit_direction.reflect(record.normal)
         } else {
             unit_direction.refract(record.normal, refraction_ratio)
         };
         let scattered = Ray::new(record.point, direction);
-        Some((Color::new(1.0, 1.0, 1.0), scattered))
+        Some((transmittance, scattered))
+    }
+
+    fn eval(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> Color {
+        let Some(distribution) = &self.distribution else {
+            return Color::new(0.0, 0.0, 0.0);
+        };
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        let wi = frame.to_local(direction.normalized());
+        distribution.eval_dielectric(wo, wi, self.eta(ray_in, record))
+            * self.transmittance(ray_in, record)
+    }
+
+    fn pdf(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> f64 {
+        let Some(distribution) = &self.distribution else {
+            return 0.0;
+        };
+        let frame = Frame::new(record.normal);
+        let wo = frame.to_local((-1.0) * ray_in.direction().normalized());
+        let wi = frame.to_local(direction.normalized());
+        distribution.pdf_dielectric(wo, wi, self.eta(ray_in, record))
+    }
+    fn is_dispersive(&self) -> bool {
+        self.refraction_index.is_dispersive()
+    }
+}
+
+// Emits light equally in all directions from both sides of the surface and absorbs all light
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Color) -> DiffuseLight {
+        DiffuseLight::textured(Arc::new(SolidColor::new(emit)))
+    }
+
+    pub fn textured(emit: Arc<dyn Texture>) -> DiffuseLight {
+        DiffuseLight { emit }
+    }
+}
+
+impl Scatter for DiffuseLight {
+    fn scatter(&self, _ray_in: &Ray, _record: &HitRecord) -> Option<(Color, Ray)> {
+        None
+    }
+
+    fn emitted(&self, _ray_in: &Ray, record: &HitRecord) -> Color {
+        self.emit.value(record.u, record.v, record.point)
+    }
+}
+
+// How `NormalMapped` changes the shading normal of the material underneath
+pub enum Perturbation {
+    // Tangent-space normals stored in [0, 1], with x along dp/du, y along dp/dv and z along
+    // the unperturbed normal. The texture should hold data rather than sRGB colors.
+    NormalMap(Arc<dyn Texture>),
+    // Height above the surface from the luminance of a texture, multiplied by `scale`
+    BumpMap {
+        height: Arc<dyn Texture>,
+        scale: f64,
+    },
+}
+
+// Adds surface detail to any material by perturbing the shading normal it sees
+pub struct NormalMapped {
+    material: Arc<dyn Scatter>,
+    perturbation: Perturbation,
+}
+
+impl NormalMapped {
+    // Step in surface coordinates for the finite differences of bump maps
+    const BUMP_DELTA: f64 = 1.0e-3;
+    // Smallest cosine allowed between the perturbed normal and the direction to the viewer
+    const MIN_VIEW_COSINE: f64 = 1.0e-2;
+
+    pub fn new(material: Arc<dyn Scatter>, perturbation: Perturbation) -> NormalMapped {
+        NormalMapped {
+            material,
+            perturbation,
+        }
+    }
+
+    fn perturbed(&self, ray_in: &Ray, record: &HitRecord) -> HitRecord {
+        let normal = record.normal;
+        let mut perturbed = match &self.perturbation {
+            Perturbation::NormalMap(texture) => {
+                let mut tangent = record.dpdu - record.dpdu.dot(normal) * normal;
+                if tangent.near_zero() {
+                    tangent = tangent_frame(normal).0;
+                }
+                let tangent = tangent.normalized();
+                let mut bitangent = normal.cross(tangent);
+                // Back faces flip the normal, keep y following v
+                if bitangent.dot(record.dpdv) < 0.0 {
+                    bitangent = (-1.0) * bitangent;
+                }
+                let value = texture.value(record.u, record.v, record.point);
+                (2.0 * value.x() - 1.0) * tangent
+                    + (2.0 * value.y() - 1.0) * bitangent
+                    + (2.0 * value.z() - 1.0) * normal
+            }
+            Perturbation::BumpMap { height, scale } => {
+                let delta = Self::BUMP_DELTA;
+                let height = |u: f64, v: f64, point| scale * height.value(u, v, point).luminance();
+                let base = height(record.u, record.v, record.point);
+                let du = height(
+                    record.u + delta,
+                    record.v,
+                    record.point + delta * record.dpdu,
+                );
+                let dv = height(
+                    record.u,
+                    record.v + delta,
+                    record.point + delta * record.dpdv,
+                );
+                let dpdu = record.dpdu + (du - base) / delta * normal;
+                let dpdv = record.dpdv + (dv - base) / delta * normal;
+                let bumped = dpdu.cross(dpdv);
+                if bumped.dot(normal) < 0.0 {
+                    (-1.0) * bumped
+                } else {
+                    bumped
+                }
+            }
+        };
+        if perturbed.near_zero() || !perturbed.x().is_finite() {
+            perturbed = normal;
+        }
+        let mut perturbed = perturbed.normalized();
+
+        // A normal facing away from the viewer would let the material see the surface from
+        // behind; bend it towards the viewer just far enough
+        let to_viewer = (-1.0) * ray_in.direction().normalized();
+        let cosine = perturbed.dot(to_viewer);
+        if cosine < Self::MIN_VIEW_COSINE {
+            perturbed = (perturbed + (Self::MIN_VIEW_COSINE - cosine) * to_viewer).normalized();
+        }
+
+        let mut record = record.clone();
+        record.normal = perturbed;
+        record
+    }
+
+    // Directions on different sides of the geometric and the shading surface would leak
+    // light through the surface or reflect it into the object
+    fn consistent(record: &HitRecord, direction: Vec3) -> bool {
+        let geometric = direction.dot(record.geometric_normal);
+        let shading = direction.dot(record.normal);
+        geometric * shading > 0.0
+    }
+}
+
+impl Scatter for NormalMapped {
+    fn scatter(&self, ray_in: &Ray, record: &HitRecord) -> Option<(Color, Ray)> {
+        let record = self.perturbed(ray_in, record);
+        let (attenuation, scattered) = self.material.scatter(ray_in, &record)?;
+        if Self::consistent(&record, scattered.direction()) {
+            Some((attenuation, scattered))
+        } else {
+            None
+        }
+    }
+
+    fn eval(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> Color {
+        let record = self.perturbed(ray_in, record);
+        if Self::consistent(&record, direction) {
+            self.material.eval(ray_in, &record, direction)
+        } else {
+            Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    fn pdf(&self, ray_in: &Ray, record: &HitRecord, direction: Vec3) -> f64 {
+        let record = self.perturbed(ray_in, record);
+        // `scatter` never returns these directions
+        if Self::consistent(&record, direction) {
+            self.material.pdf(ray_in, &record, direction)
+        } else {
+            0.0
+        }
+    }
+
+    fn emitted(&self, ray_in: &Ray, record: &HitRecord) -> Color {
+        let record = self.perturbed(ray_in, record);
+        self.material.emitted(ray_in, &record)
+    }
+    fn is_dispersive(&self) -> bool {
+        self.material.is_dispersive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random;
+    use crate::vec::Point3;
+
+    const SAMPLES: usize = 20_000;
+    const COSINES: [f64; 6] = [1.0, 0.75, 0.5, 0.25, 0.1, 0.02];
+
+    // A hit at `point` with the unit square as its parametrization around it
+    fn record(
+        material: &Arc<dyn Scatter>,
+        point: Point3,
+        normal: Vec3,
+        front_face: bool,
+    ) -> HitRecord {
+        HitRecord {
+            point,
+            normal,
+            geometric_normal: normal,
+            material: material.clone(),
+            // Not read by any material
+            time: 0.0,
+            front_face,
+            u: 0.5,
+            v: 0.5,
+            dpdu: Vec3::new(1.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 1.0, 0.0),
+            object_id: 0,
+            primitive_id: 0,
+        }
+    }
+
+    // White furnace test: under uniform white light a material can at most scatter what
+    // arrives, so the mean scatter weight for light leaving at `cosine` to the normal must not
+    // exceed one. Returns the largest channel of the mean and its standard error.
+    fn albedo(material: &Arc<dyn Scatter>, cosine: f64, front_face: bool) -> (f64, f64) {
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let wo = Vec3::new((1.0 - cosine * cosine).sqrt(), 0.0, cosine);
+        let ray = Ray::new(wo, (-1.0) * wo);
+        let record = record(material, Vec3::new(0.0, 0.0, 0.0), normal, front_face);
+
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        let mut sum_squares = 0.0;
+        for _ in 0..SAMPLES {
+            if let Some((weight, _)) = material.scatter(&ray, &record) {
+                let largest = weight.x().max(weight.y()).max(weight.z());
+                sum += weight;
+                sum_squares += largest * largest;
+            }
+        }
+        let n = SAMPLES as f64;
+        let mean = sum / n;
+        let largest = mean.x().max(mean.y()).max(mean.z());
+        let variance = (sum_squares / n - largest * largest).max(0.0);
+        (largest, (variance / n).sqrt())
+    }
+
+    fn assert_conserves_energy(name: &str, material: Arc<dyn Scatter>) {
+        random::seed(0);
+        // Both sides matter for materials that let light through
+        for front_face in [true, false] {
+            for cosine in COSINES {
+                let (albedo, error) = albedo(&material, cosine, front_face);
+                // Allow for the noise of the estimate
+                assert!(
+                    albedo <= 1.0 + 4.0 * error + 1.0e-3,
+                    "{name} scatters {albedo:.3} at cosine {cosine} (front face: {front_face})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn microfacet_conserves_energy() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        for roughness in [0.05, 0.5, 1.0] {
+            assert_conserves_energy("plastic", Arc::new(Microfacet::new(white, roughness, 0.0)));
+            assert_conserves_energy("metal", Arc::new(Microfacet::new(white, roughness, 1.0)));
+        }
+    }
+
+    #[test]
+    fn lambertian_conserves_energy() {
+        assert_conserves_energy(
+            "lambertian",
+            Arc::new(Lambertian::new(Color::new(1.0, 1.0, 1.0))),
+        );
+    }
+
+    #[test]
+    fn dielectric_conserves_energy() {
+        assert_conserves_energy("smooth glass", Arc::new(Dielectric::new(1.5)));
+        for roughness in [0.5, 1.0] {
+            let glass = Dielectric::new(1.5).with_roughness(roughness);
+            assert_conserves_energy("rough glass", Arc::new(glass));
+        }
+    }
+
+    #[test]
+    fn principled_conserves_energy() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        let materials = [
+            ("default", Principled::new(white)),
+            ("smooth", Principled::new(white).with_roughness(0.0)),
+            (
+                "rough metal",
+                Principled::new(white)
+                    .with_metallic(1.0)
+                    .with_roughness(1.0),
+            ),
+            (
+                "specular",
+                Principled::new(white)
+                    .with_roughness(0.2)
+                    .with_specular(1.0, 1.0),
+            ),
+            ("sheen", Principled::new(white).with_sheen(1.0)),
+            (
+                "clearcoat",
+                Principled::new(white)
+                    .with_roughness(0.3)
+                    .with_clearcoat(1.0, 1.0),
+            ),
+            (
+                "hazy clearcoat",
+                Principled::new(white)
+                    .with_metallic(1.0)
+                    .with_roughness(0.1)
+                    .with_clearcoat(1.0, 0.0),
+            ),
+            (
+                "glass",
+                Principled::new(white)
+                    .with_roughness(0.0)
+                    .with_transmission(1.0, 1.5),
+            ),
+            (
+                "frosted glass",
+                Principled::new(white)
+                    .with_roughness(0.5)
+                    .with_transmission(1.0, 1.5),
+            ),
+            (
+                "everything",
+                Principled::new(white)
+                    .with_metallic(0.3)
+                    .with_roughness(0.4)
+                    .with_specular(1.0, 0.5)
+                    .with_sheen(1.0)
+                    .with_clearcoat(1.0, 0.5)
+                    .with_transmission(0.5, 1.5),
+            ),
+        ];
+        for (name, material) in materials {
+            assert_conserves_energy(name, Arc::new(material));
+        }
+    }
+
+    #[test]
+    fn absorption_follows_slab_thickness() {
+        let tint = Color::new(0.8, 0.5, 0.2);
+        let tint_distance = 2.0;
+        let glass: Arc<dyn Scatter> =
+            Arc::new(Dielectric::new(1.5).with_absorption(tint, tint_distance));
+        for thickness in [0.5, 2.0, 5.0] {
+            // Straight through a slab from its front at z = 0 to its back
+            let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+            let record = record(
+                &glass,
+                Vec3::new(0.0, 0.0, thickness),
+                Vec3::new(0.0, 0.0, -1.0),
+                false,
+            );
+            let (weight, _) = glass.scatter(&ray, &record).unwrap();
+            let expected =
+                [tint.x(), tint.y(), tint.z()].map(|c| c.powf(thickness / tint_distance));
+            for (channel, expected) in expected.into_iter().enumerate() {
+                assert!(
+                    (weight[channel] - expected).abs() < 1.0e-9,
+                    "{thickness} thick: {} instead of {expected}",
+                    weight[channel]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn normal_mapped_pdf_skips_inconsistent_directions() {
+        // Tilted far enough that some directions are above one surface and below the other
+        let tilt = Arc::new(SolidColor::new(Color::new(1.0, 0.5, 0.6)));
+        let material: Arc<dyn Scatter> = Arc::new(NormalMapped::new(
+            Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5))),
+            Perturbation::NormalMap(tilt),
+        ));
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let record = record(&material, Vec3::new(0.0, 0.0, 0.0), normal, true);
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        // Below the geometric surface but above the shading one
+        let below = Vec3::new(1.0, 0.0, -0.2);
+        assert_eq!(material.pdf(&ray, &record, below), 0.0);
+        assert_eq!(material.eval(&ray, &record, below).luminance(), 0.0);
+        let above = Vec3::new(0.2, 0.0, 1.0);
+        assert!(material.pdf(&ray, &record, above) > 0.0);
+    }
+
+    #[test]
+    fn nonsensical_indices_pass_light_straight() {
+        let glass: Arc<dyn Scatter> = Arc::new(Dielectric::dispersive(RefractiveIndex::Cauchy {
+            a: -1.0,
+            b: 0.0,
+        }));
+        let normal = Vec3::new(0.0, 0.0, 1.0);
+        let record = record(&glass, Vec3::new(0.0, 0.0, 0.0), normal, true);
+        let direction = Vec3::new(0.6, 0.0, -0.8);
+        let ray = Ray::new((-1.0) * direction, direction);
+        // Schlick's approximation still reflects a tiny fraction without a change of index,
+        // but nothing may be bent
+        let mirrored = direction.reflect(normal);
+        let mut straight = 0;
+        for _ in 0..100 {
+            let (_, scattered) = glass.scatter(&ray, &record).unwrap();
+            let scattered = scattered.direction().normalized();
+            if (scattered - direction).length() < 1.0e-9 {
+                straight += 1;
+            } else {
+                assert!((scattered - mirrored).length() < 1.0e-9);
+            }
+        }
+        assert!(straight >= 90);
     }
 }
@@ -0,0 +1,214 @@
+// microfacet.rs
+// GGX (Trowbridge-Reitz) microfacet distribution. Directions are given in a local shading
+// frame with the macro surface normal along +z.
+use super::vec::{Color, Vec3};
+
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy)]
+pub struct Ggx {
+    alpha: f64,
+}
+
+impl Ggx {
+    // Perfectly smooth surfaces are approximated by a very narrow lobe, so they can still be
+    // evaluated for light sampling
+    const MIN_ALPHA: f64 = 1.0e-3;
+
+    // `roughness` in [0, 1] as in metal/roughness workflows, squared to get the GGX alpha
+    pub fn new(roughness: f64) -> Ggx {
+        Ggx {
+            alpha: (roughness * roughness).max(Self::MIN_ALPHA),
+        }
+    }
+
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    // Density of micro normals `m` per projected area of the macro surface
+    pub fn d(&self, m: Vec3) -> f64 {
+        if m.z() <= 0.0 {
+            return 0.0;
+        }
+        let alpha2 = self.alpha * self.alpha;
+        let cos2 = m.z() * m.z();
+        let denominator = cos2 * (alpha2 - 1.0) + 1.0;
+        alpha2 / (PI * denominator * denominator)
+    }
+
+    fn lambda(&self, w: Vec3) -> f64 {
+        let cos2 = w.z() * w.z();
+        if cos2 <= 0.0 {
+            return f64::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    // Smith masking of micro normals as seen from `w`
+    pub fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // Height-correlated Smith masking-shadowing for the pair of directions
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Samples a micro normal visible from `wo`, which must lie above the surface
+    // (Heitz, "Sampling the GGX Distribution of Visible Normals", 2018)
+    pub fn sample_visible_normal(&self, wo: Vec3, u1: f64, u2: f64) -> Vec3 {
+        // Stretch the view direction to the configuration of a hemisphere
+        let view = Vec3::new(self.alpha * wo.x(), self.alpha * wo.y(), wo.z()).normalized();
+        let length2 = view.x() * view.x() + view.y() * view.y();
+        let t1 = if length2 > 0.0 {
+            Vec3::new(-view.y(), view.x(), 0.0) / length2.sqrt()
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = view.cross(t1);
+
+        // Uniform point on the projected disk, warped towards the visible half
+        let radius = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = radius * phi.cos();
+        let s = 0.5 * (1.0 + view.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * radius * phi.sin();
+        let normal = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * view;
+
+        // Unstretch
+        Vec3::new(
+            self.alpha * normal.x(),
+            self.alpha * normal.y(),
+            normal.z().max(0.0),
+        )
+        .normalized()
+    }
+
+    // Density of `sample_visible_normal` returning `m`
+    pub fn visible_normal_pdf(&self, wo: Vec3, m: Vec3) -> f64 {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(m).max(0.0) * self.d(m) / wo.z()
+    }
+
+    // Density of reflecting `wo` about a visible normal to get `wi`
+    pub fn reflection_pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        let half = (wo + wi).normalized();
+        let cosine = wo.dot(half);
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        self.visible_normal_pdf(wo, half) / (4.0 * cosine)
+    }
+
+    // BSDF times the cosine of a rough interface between dielectrics, reflecting above and
+    // transmitting below the surface. `eta` is the ratio of the refractive indices below over
+    // above. Like the smooth `Dielectric`, transmitted radiance is not scaled by 1/eta².
+    pub fn eval_dielectric(&self, wo: Vec3, wi: Vec3, eta: f64) -> f64 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+        if wi.z() > 0.0 {
+            let half = (wo + wi).normalized();
+            let fresnel = fresnel_dielectric(wo.dot(half), eta);
+            return fresnel * self.d(half) * self.g(wo, wi) / (4.0 * wo.z());
+        }
+        let Some(half) = transmission_half_vector(wo, wi, eta) else {
+            return 0.0;
+        };
+        let fresnel = fresnel_dielectric(wo.dot(half), eta);
+        let denominator = wo.dot(half) + eta * wi.dot(half);
+        eta * eta * (1.0 - fresnel) * self.d(half) * self.g(wo, wi) * wo.dot(half) * (-wi.dot(half))
+            / (wo.z() * denominator * denominator)
+    }
+
+    // Density of `sample_dielectric` returning `wi`
+    pub fn pdf_dielectric(&self, wo: Vec3, wi: Vec3, eta: f64) -> f64 {
+        if wo.z() <= 0.0 || wi.z() == 0.0 {
+            return 0.0;
+        }
+        if wi.z() > 0.0 {
+            let half = (wo + wi).normalized();
+            return fresnel_dielectric(wo.dot(half), eta) * self.reflection_pdf(wo, wi);
+        }
+        let Some(half) = transmission_half_vector(wo, wi, eta) else {
+            return 0.0;
+        };
+        let fresnel = fresnel_dielectric(wo.dot(half), eta);
+        let denominator = wo.dot(half) + eta * wi.dot(half);
+        // Refraction compresses the solid angle of the half vectors by this factor
+        let jacobian = eta * eta * (-wi.dot(half)) / (denominator * denominator);
+        (1.0 - fresnel) * self.visible_normal_pdf(wo, half) * jacobian
+    }
+
+    // Picks a visible normal, then reflects or refracts about it in proportion to the Fresnel
+    // reflectance
+    pub fn sample_dielectric(&self, wo: Vec3, eta: f64, u1: f64, u2: f64, u3: f64) -> Vec3 {
+        let normal = self.sample_visible_normal(wo, u1, u2);
+        let fresnel = fresnel_dielectric(wo.dot(normal), eta);
+        match refract(wo, normal, eta) {
+            Some(refracted) if u3 >= fresnel => refracted,
+            _ => reflect(wo, normal),
+        }
+    }
+}
+
+// Half vector of a refraction from `wo` to `wi`, on the side of `wo`. None when the pair
+// could not have come from a micro normal facing `wo`.
+fn transmission_half_vector(wo: Vec3, wi: Vec3, eta: f64) -> Option<Vec3> {
+    let half = wo + eta * wi;
+    if half.near_zero() {
+        return None;
+    }
+    let half = half.normalized();
+    let half = if half.z() < 0.0 { (-1.0) * half } else { half };
+    if wo.dot(half) <= 0.0 || wi.dot(half) >= 0.0 {
+        return None;
+    }
+    Some(half)
+}
+
+// Mirror image of `wo` about the normal `m`; both point away from the surface
+pub fn reflect(wo: Vec3, m: Vec3) -> Vec3 {
+    2.0 * wo.dot(m) * m - wo
+}
+
+// Direction of `wo` refracted through the normal `m` into a medium with relative index `eta`,
+// or None for total internal reflection
+pub fn refract(wo: Vec3, m: Vec3, eta: f64) -> Option<Vec3> {
+    let cos_i = wo.dot(m);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some((cos_i / eta - cos_t) * m - wo / eta)
+}
+
+// Schlick's approximation of the Fresnel reflectance for reflectance `f0` at normal incidence
+pub fn schlick(f0: Color, cosine: f64) -> Color {
+    let weight = schlick_weight(cosine);
+    (1.0 - weight) * f0 + weight * Color::new(1.0, 1.0, 1.0)
+}
+
+// Weight of white in Schlick's approximation, (1 - cosine)^5
+pub fn schlick_weight(cosine: f64) -> f64 {
+    (1.0 - cosine.clamp(0.0, 1.0)).powi(5)
+}
+
+// Exact Fresnel reflectance of unpolarized light at a dielectric interface, where `eta` is
+// the ratio of the refractive indices of the far over the near side
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
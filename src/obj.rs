@@ -0,0 +1,641 @@
+// obj.rs
+use super::material::{Dielectric, Lambertian, Metal, Scatter};
+use super::triangle::{Face, TriangleMesh};
+use super::vec::{Color, Point3, Vec3};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+    IndexOutOfRange {
+        path: PathBuf,
+        line: usize,
+        index: i64,
+        count: usize,
+    },
+    MissingMaterialLibrary {
+        path: PathBuf,
+        line: usize,
+        library: PathBuf,
+    },
+    UnknownMaterial {
+        path: PathBuf,
+        line: usize,
+        name: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io { path, source } => {
+                write!(f, "{}: {}", path.display(), source)
+            }
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+            ObjError::IndexOutOfRange {
+                path,
+                line,
+                index,
+                count,
+            } => write!(
+                f,
+                "{}:{}: index {} is out of range ({} elements defined)",
+                path.display(),
+                line,
+                index,
+                count
+            ),
+            ObjError::MissingMaterialLibrary {
+                path,
+                line,
+                library,
+            } => write!(
+                f,
+                "{}:{}: material library {} could not be opened",
+                path.display(),
+                line,
+                library.display()
+            ),
+            ObjError::UnknownMaterial { path, line, name } => {
+                write!(
+                    f,
+                    "{}:{}: unknown material `{}`",
+                    path.display(),
+                    line,
+                    name
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+// Loads a Wavefront OBJ file and its MTL libraries into one mesh per group and material.
+// Faces without a material use `default_material`.
+pub fn load(
+    path: &Path,
+    default_material: Arc<dyn Scatter>,
+) -> Result<Vec<TriangleMesh>, ObjError> {
+    let source = read_file(path)?;
+
+    let mut positions: Vec<Point3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<[f64; 2]> = Vec::new();
+    let mut materials: HashMap<String, Arc<dyn Scatter>> = HashMap::new();
+
+    // Faces are grouped per (group, material) pair, in order of first appearance
+    let mut groups: Vec<Group> = Vec::new();
+    let mut group_name = String::new();
+    let mut material_name: Option<String> = None;
+    let mut current: Option<usize> = None;
+
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let parser = LineParser {
+            path,
+            line: line_number,
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => positions.push(parser.vector(&arguments)?),
+            "vn" => normals.push(parser.vector(&arguments)?),
+            "vt" => {
+                if arguments.is_empty() {
+                    return Err(parser.error("expected at least one texture coordinate"));
+                }
+                let u = parser.float(arguments[0])?;
+                let v = match arguments.get(1) {
+                    Some(value) => parser.float(value)?,
+                    None => 0.0,
+                };
+                uvs.push([u, v]);
+            }
+            "f" => {
+                if arguments.len() < 3 {
+                    return Err(parser.error("a face needs at least three vertices"));
+                }
+                let vertices = arguments
+                    .iter()
+                    .map(|vertex| {
+                        parser.face_vertex(vertex, positions.len(), uvs.len(), normals.len())
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                let group = match current {
+                    Some(group) => group,
+                    None => {
+                        // usemtl has already checked that the material exists
+                        let material = match &material_name {
+                            Some(name) => materials[name].clone(),
+                            None => default_material.clone(),
+                        };
+                        groups.push(Group {
+                            name: group_name.clone(),
+                            material_name: material_name.clone(),
+                            material,
+                            faces: Vec::new(),
+                        });
+                        groups.len() - 1
+                    }
+                };
+                current = Some(group);
+
+                // Polygons are triangulated as a fan around their first vertex
+                for i in 1..vertices.len() - 1 {
+                    let corners = [vertices[0], vertices[i], vertices[i + 1]];
+                    groups[group].faces.push(Face {
+                        positions: corners.map(|corner| corner.position),
+                        uvs: all_some(corners.map(|corner| corner.uv)),
+                        normals: all_some(corners.map(|corner| corner.normal)),
+                    });
+                }
+            }
+            "g" | "o" => {
+                group_name = arguments.join(" ");
+                current = find_group(&groups, &group_name, &material_name);
+            }
+            "usemtl" => {
+                let name = arguments.join(" ");
+                if !materials.contains_key(&name) {
+                    return Err(ObjError::UnknownMaterial {
+                        path: path.to_path_buf(),
+                        line: line_number,
+                        name,
+                    });
+                }
+                material_name = Some(name);
+                current = find_group(&groups, &group_name, &material_name);
+            }
+            "mtllib" => {
+                if arguments.is_empty() {
+                    return Err(parser.error("expected a material library file name"));
+                }
+                let library = path
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(arguments.join(" "));
+                if !library.is_file() {
+                    return Err(ObjError::MissingMaterialLibrary {
+                        path: path.to_path_buf(),
+                        line: line_number,
+                        library,
+                    });
+                }
+                materials.extend(load_materials(&library)?);
+            }
+            // Smoothing groups, lines, points and free-form geometry are not rendered
+            _ => {}
+        }
+    }
+
+    Ok(groups
+        .into_iter()
+        .map(|group| group.into_mesh(&positions, &normals, &uvs))
+        .collect())
+}
+
+struct Group {
+    name: String,
+    material_name: Option<String>,
+    material: Arc<dyn Scatter>,
+    faces: Vec<Face>,
+}
+
+impl Group {
+    // Copies only the vertex data referenced by this group so each mesh owns compact buffers
+    fn into_mesh(self, positions: &[Point3], normals: &[Vec3], uvs: &[[f64; 2]]) -> TriangleMesh {
+        let mut position_map = Remap::default();
+        let mut normal_map = Remap::default();
+        let mut uv_map = Remap::default();
+
+        let faces = self
+            .faces
+            .iter()
+            .map(|face| Face {
+                positions: face.positions.map(|index| position_map.get(index)),
+                normals: face
+                    .normals
+                    .map(|indices| indices.map(|index| normal_map.get(index))),
+                uvs: face
+                    .uvs
+                    .map(|indices| indices.map(|index| uv_map.get(index))),
+            })
+            .collect();
+
+        TriangleMesh::new(
+            position_map.collect(positions),
+            normal_map.collect(normals),
+            uv_map.collect(uvs),
+            faces,
+            self.material,
+        )
+        .expect("face indices are checked while parsing and remapped densely")
+    }
+}
+
+#[derive(Default)]
+struct Remap {
+    indices: HashMap<usize, usize>,
+    order: Vec<usize>,
+}
+
+impl Remap {
+    fn get(&mut self, index: usize) -> usize {
+        *self.indices.entry(index).or_insert_with(|| {
+            self.order.push(index);
+            self.order.len() - 1
+        })
+    }
+
+    fn collect<T: Copy>(&self, values: &[T]) -> Vec<T> {
+        self.order.iter().map(|&index| values[index]).collect()
+    }
+}
+
+fn find_group(groups: &[Group], name: &str, material_name: &Option<String>) -> Option<usize> {
+    groups
+        .iter()
+        .position(|group| group.name == name && &group.material_name == material_name)
+}
+
+fn all_some(values: [Option<usize>; 3]) -> Option<[usize; 3]> {
+    match values {
+        [Some(a), Some(b), Some(c)] => Some([a, b, c]),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct FaceVertex {
+    position: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+struct LineParser<'a> {
+    path: &'a Path,
+    line: usize,
+}
+
+impl LineParser<'_> {
+    fn error(&self, message: &str) -> ObjError {
+        ObjError::Parse {
+            path: self.path.to_path_buf(),
+            line: self.line,
+            message: message.to_string(),
+        }
+    }
+
+    fn float(&self, token: &str) -> Result<f64, ObjError> {
+        token
+            .parse()
+            .map_err(|_| self.error(&format!("`{}` is not a number", token)))
+    }
+
+    fn vector(&self, arguments: &[&str]) -> Result<Vec3, ObjError> {
+        if arguments.len() < 3 {
+            return Err(self.error("expected three coordinates"));
+        }
+        Ok(Vec3::new(
+            self.float(arguments[0])?,
+            self.float(arguments[1])?,
+            self.float(arguments[2])?,
+        ))
+    }
+
+    fn color(&self, arguments: &[&str]) -> Result<Color, ObjError> {
+        match arguments.len() {
+            1 => {
+                let value = self.float(arguments[0])?;
+                Ok(Color::new(value, value, value))
+            }
+            _ => self.vector(arguments),
+        }
+    }
+
+    // Resolves a 1-based (or negative, relative) OBJ index against `count` elements
+    fn index(&self, token: &str, count: usize) -> Result<usize, ObjError> {
+        let index: i64 = token
+            .parse()
+            .map_err(|_| self.error(&format!("`{}` is not a valid index", token)))?;
+        let resolved = if index < 0 {
+            count as i64 + index
+        } else {
+            index - 1
+        };
+        if index == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(ObjError::IndexOutOfRange {
+                path: self.path.to_path_buf(),
+                line: self.line,
+                index,
+                count,
+            });
+        }
+        Ok(resolved as usize)
+    }
+
+    fn face_vertex(
+        &self,
+        token: &str,
+        position_count: usize,
+        uv_count: usize,
+        normal_count: usize,
+    ) -> Result<FaceVertex, ObjError> {
+        let mut parts = token.split('/');
+        let position = self.index(parts.next().unwrap_or(""), position_count)?;
+        let uv = match parts.next() {
+            Some("") | None => None,
+            Some(part) => Some(self.index(part, uv_count)?),
+        };
+        let normal = match parts.next() {
+            Some("") | None => None,
+            Some(part) => Some(self.index(part, normal_count)?),
+        };
+        if parts.next().is_some() {
+            return Err(self.error(&format!("malformed face vertex `{}`", token)));
+        }
+        Ok(FaceVertex {
+            position,
+            uv,
+            normal,
+        })
+    }
+}
+
+#[derive(Default)]
+struct MaterialDescription {
+    diffuse: Option<Color>,
+    specular: Option<Color>,
+    shininess: Option<f64>,
+    refraction_index: Option<f64>,
+    dissolve: Option<f64>,
+    illumination: Option<u32>,
+}
+
+impl MaterialDescription {
+    // Maps the MTL illumination model onto the closest available material
+    fn build(&self) -> Arc<dyn Scatter> {
+        let diffuse = self.diffuse.unwrap_or(Color::new(0.8, 0.8, 0.8));
+        let transparent = self.dissolve.is_some_and(|dissolve| dissolve < 1.0)
+            || matches!(self.illumination, Some(4 | 6 | 7 | 9));
+        let reflective = matches!(self.illumination, Some(3 | 5 | 8));
+
+        if transparent {
+            Arc::new(Dielectric::new(self.refraction_index.unwrap_or(1.5)))
+        } else if reflective {
+            let albedo = match self.specular {
+                Some(specular) if !specular.near_zero() => specular,
+                _ => diffuse,
+            };
+            // Convert the Phong exponent into a rough equivalent of the fuzz radius
+            let shininess = self.shininess.unwrap_or(1000.0).max(0.0);
+            let fuzz = (2.0 / (shininess + 2.0)).sqrt().min(1.0);
+            Arc::new(Metal::new(albedo, fuzz))
+        } else {
+            Arc::new(Lambertian::new(diffuse))
+        }
+    }
+}
+
+fn load_materials(path: &Path) -> Result<HashMap<String, Arc<dyn Scatter>>, ObjError> {
+    let source = read_file(path)?;
+
+    let mut descriptions: Vec<(String, MaterialDescription)> = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let parser = LineParser {
+            path,
+            line: index + 1,
+        };
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            if arguments.is_empty() {
+                return Err(parser.error("expected a material name"));
+            }
+            descriptions.push((arguments.join(" "), MaterialDescription::default()));
+            continue;
+        }
+
+        let Some((_, description)) = descriptions.last_mut() else {
+            return Err(parser.error(&format!("`{}` appears before any newmtl", keyword)));
+        };
+        let first = || {
+            arguments
+                .first()
+                .ok_or_else(|| parser.error(&format!("`{}` expects a value", keyword)))
+        };
+
+        match keyword {
+            "Kd" => description.diffuse = Some(parser.color(&arguments)?),
+            "Ks" => description.specular = Some(parser.color(&arguments)?),
+            "Ns" => description.shininess = Some(parser.float(first()?)?),
+            "Ni" => description.refraction_index = Some(parser.float(first()?)?),
+            "d" => description.dissolve = Some(parser.float(first()?)?),
+            "Tr" => description.dissolve = Some(1.0 - parser.float(first()?)?),
+            "illum" => {
+                let token = first()?;
+                let model = token.parse().map_err(|_| {
+                    parser.error(&format!("`{}` is not a valid illumination model", token))
+                })?;
+                description.illumination = Some(model);
+            }
+            // Other statements (ambient color, texture maps, ...) have no equivalent yet
+            _ => {}
+        }
+    }
+
+    Ok(descriptions
+        .into_iter()
+        .map(|(name, description)| (name, description.build()))
+        .collect())
+}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    fs::read_to_string(path).map_err(|source| ObjError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `files` as (name, contents) to a directory of their own, loads the first and
+    // removes the directory again
+    fn load_files(name: &str, files: &[(&str, &str)]) -> Result<Vec<TriangleMesh>, ObjError> {
+        let directory = std::env::temp_dir().join(format!(
+            "rust_raytracer_obj_{}_{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        for (file, contents) in files {
+            fs::write(directory.join(file), contents).unwrap();
+        }
+        let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let meshes = load(&directory.join(files[0].0), material);
+        fs::remove_dir_all(&directory).unwrap();
+        meshes
+    }
+
+    fn load_source(name: &str, source: &str) -> Result<Vec<TriangleMesh>, ObjError> {
+        load_files(name, &[("mesh.obj", source)])
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn polygons_are_split_by_group_and_material() {
+        let source = format!(
+            "mtllib square.mtl\n{}vt 0 0\nvt 1 0\nvt 1 1\n\
+             f 1/1 2/2 3/3 4/1\nusemtl red\nf -4 -3 -2\ng other\nf 1 2 3\n",
+            SQUARE
+        );
+        let library = "newmtl red\nKd 1 0 0\n";
+        let meshes =
+            load_files("groups", &[("mesh.obj", &source), ("square.mtl", library)]).unwrap();
+        let sizes: Vec<usize> = meshes.iter().map(|mesh| mesh.len()).collect();
+        assert_eq!(sizes, [2, 1, 1]);
+    }
+
+    #[test]
+    fn missing_files_are_io_errors() {
+        let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        let path = Path::new("no/such/mesh.obj");
+        match load(path, material) {
+            Err(ObjError::Io { path: reported, .. }) => assert_eq!(reported, path),
+            _ => panic!("expected an I/O error"),
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_parse_errors() {
+        for (source, line, message) in [
+            ("v 0 0 x\n", 1, "`x` is not a number"),
+            ("v 0 0\n", 1, "expected three coordinates"),
+            ("vt\n", 1, "expected at least one texture coordinate"),
+            (
+                &format!("{}f 1 2\n", SQUARE),
+                5,
+                "a face needs at least three vertices",
+            ),
+            (
+                &format!("{}f 1 2 a\n", SQUARE),
+                5,
+                "`a` is not a valid index",
+            ),
+            (
+                &format!("{}f 1 2 3///\n", SQUARE),
+                5,
+                "malformed face vertex `3///`",
+            ),
+            ("mtllib\n", 1, "expected a material library file name"),
+        ] {
+            match load_source("parse", source) {
+                Err(ObjError::Parse {
+                    line: reported,
+                    message: reported_message,
+                    ..
+                }) => assert_eq!((reported, reported_message.as_str()), (line, message)),
+                _ => panic!("`{}` was not a parse error", source.trim()),
+            }
+        }
+    }
+
+    #[test]
+    fn indices_are_checked_against_what_is_defined() {
+        // 1-based, relative from the end, and per kind of vertex data
+        for (face, index, count) in [
+            ("f 0 1 2", 0, 4),
+            ("f 1 2 5", 5, 4),
+            ("f 1 2 -5", -5, 4),
+            ("f 1/1 2/1 3/1", 1, 0),
+            ("f 1//2 2//1 3//1", 2, 1),
+        ] {
+            let source = format!("{}vn 0 0 1\n{}\n", SQUARE, face);
+            match load_source("indices", &source) {
+                Err(ObjError::IndexOutOfRange {
+                    line,
+                    index: reported,
+                    count: defined,
+                    ..
+                }) => assert_eq!((line, reported, defined), (6, index, count)),
+                _ => panic!("`{}` was accepted", face),
+            }
+        }
+    }
+
+    #[test]
+    fn material_libraries_must_exist() {
+        match load_source("library", "v 0 0 0\nmtllib missing.mtl\n") {
+            Err(ObjError::MissingMaterialLibrary { line, library, .. }) => {
+                assert_eq!(line, 2);
+                assert!(library.ends_with("missing.mtl"));
+            }
+            _ => panic!("expected a missing material library"),
+        }
+    }
+
+    #[test]
+    fn materials_must_be_defined() {
+        let files = [
+            ("mesh.obj", "mtllib square.mtl\nusemtl blue\n"),
+            ("square.mtl", "newmtl red\nKd 1 0 0\n"),
+        ];
+        match load_files("material", &files) {
+            Err(ObjError::UnknownMaterial { line, name, .. }) => {
+                assert_eq!((line, name.as_str()), (2, "blue"))
+            }
+            _ => panic!("expected an unknown material"),
+        }
+        // Errors in the library are reported against the library
+        let files = [
+            ("mesh.obj", "mtllib square.mtl\n"),
+            ("square.mtl", "Kd 1 0 0\n"),
+        ];
+        match load_files("library_error", &files) {
+            Err(ObjError::Parse { path, line, .. }) => {
+                assert!(path.ends_with("square.mtl"));
+                assert_eq!(line, 1);
+            }
+            _ => panic!("expected a parse error in the library"),
+        }
+    }
+}
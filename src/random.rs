@@ -0,0 +1,23 @@
+// random.rs
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::ops::Range;
+
+// Every render thread owns its generator, so sampling never contends on shared state
+thread_local! {
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+// Reseeds the calling thread's generator, making the samples that follow reproducible
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+pub fn random() -> f64 {
+    RNG.with(|rng| rng.borrow_mut().gen())
+}
+
+pub fn random_range(range: Range<f64>) -> f64 {
+    RNG.with(|rng| rng.borrow_mut().gen_range(range))
+}
@@ -5,6 +5,8 @@ pub struct Ray {
     origin: Point3,
     direction: Vec3,
     inv_direction: Vec3,
+    // Hero wavelength in nm of a path in spectral rendering
+    wavelength: Option<f64>,
 }
 
 impl Ray {
@@ -13,9 +15,19 @@ impl Ray {
             origin,
             direction,
             inv_direction: direction.invert(),
+            wavelength: None,
         }
     }
 
+    pub fn with_wavelength(mut self, wavelength: Option<f64>) -> Ray {
+        self.wavelength = wavelength;
+        self
+    }
+
+    pub fn wavelength(&self) -> Option<f64> {
+        self.wavelength
+    }
+
     pub fn origin(&self) -> Point3 {
         self.origin
     }
@@ -0,0 +1,565 @@
+// render.rs
+use super::camera::Camera;
+use super::environment::{Environment, Gradient, SolidColor};
+use super::hit::{Hit, HitRecord};
+use super::image::Image;
+use super::light::Light;
+use super::random;
+use super::ray::Ray;
+use super::sampling::power_heuristic;
+use super::spectrum::{self, Wavelengths};
+use super::vec::{Color, Point3, Vec3};
+
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const TILE_SIZE: usize = 32;
+
+#[derive(Clone)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u64,
+    pub max_depth: u64,
+    pub seed: u64,
+    // Trace sampled wavelengths instead of RGB and accumulate pixels in CIE XYZ
+    pub spectral: bool,
+}
+
+// Settings that no image can be rendered with
+#[derive(Debug)]
+pub enum RenderError {
+    // Pixels are spread from one edge of the view to the other, which takes two in each direction
+    ImageTooSmall { width: usize, height: usize },
+    NoSamples,
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::ImageTooSmall { width, height } => write!(
+                f,
+                "image of {}x{} pixels is too small, it needs at least 2x2",
+                width, height
+            ),
+            RenderError::NoSamples => write!(f, "at least one sample per pixel is needed"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+type ProgressCallback = Box<dyn Fn(usize, usize) + Send + Sync>;
+
+pub struct Renderer {
+    camera: Camera,
+    world: Arc<dyn Hit>,
+    settings: RenderSettings,
+    environment: Arc<dyn Environment>,
+    // Emissive objects that are sampled directly at every bounce off a non-specular surface
+    lights: Vec<Arc<dyn Hit>>,
+    // Point, spot and directional lights, all of which are evaluated at every such bounce
+    punctual_lights: Vec<Arc<dyn Light>>,
+    progress: Option<ProgressCallback>,
+}
+
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Renderer {
+    pub fn new(
+        camera: Camera,
+        world: Arc<dyn Hit>,
+        settings: RenderSettings,
+    ) -> Result<Renderer, RenderError> {
+        if settings.width < 2 || settings.height < 2 {
+            return Err(RenderError::ImageTooSmall {
+                width: settings.width,
+                height: settings.height,
+            });
+        }
+        if settings.samples_per_pixel == 0 {
+            return Err(RenderError::NoSamples);
+        }
+        Ok(Renderer {
+            camera,
+            world,
+            settings,
+            environment: Arc::new(Gradient::default()),
+            lights: Vec::new(),
+            punctual_lights: Vec::new(),
+            progress: None,
+        })
+    }
+
+    // Replaces the default sky gradient seen by rays that leave the scene
+    pub fn with_environment(mut self, environment: Arc<dyn Environment>) -> Renderer {
+        self.environment = environment;
+        self
+    }
+
+    // Shorthand for a solid color environment
+    pub fn with_background(self, background: Color) -> Renderer {
+        self.with_environment(Arc::new(SolidColor::new(background)))
+    }
+
+    // Emissive objects to sample explicitly. They must be part of the world as well;
+    // shapes left out are still found by scattered rays, only with more noise.
+    pub fn with_lights(mut self, lights: Vec<Arc<dyn Hit>>) -> Renderer {
+        self.lights = lights;
+        self
+    }
+
+    pub fn with_punctual_lights(mut self, lights: Vec<Arc<dyn Light>>) -> Renderer {
+        self.punctual_lights = lights;
+        self
+    }
+
+    // Called with (finished tiles, total tiles) from the render threads after every tile
+    pub fn on_progress(
+        mut self,
+        callback: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) -> Renderer {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    // Renders the image in parallel tiles on the current rayon thread pool.
+    // The result holds the average radiance of every pixel, without any tone mapping.
+    pub fn render(&self) -> Image {
+        let settings = &self.settings;
+        let mut tiles = Vec::new();
+        for y in (0..settings.height).step_by(TILE_SIZE) {
+            for x in (0..settings.width).step_by(TILE_SIZE) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(settings.width - x),
+                    height: TILE_SIZE.min(settings.height - y),
+                });
+            }
+        }
+
+        let tile_count = tiles.len();
+        let finished = AtomicUsize::new(0);
+        let rendered: Vec<(Tile, Vec<Color>)> = tiles
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, tile)| {
+                // Seeding per tile keeps the result independent of the thread scheduling
+                random::seed(tile_seed(settings.seed, index));
+                let pixels = self.render_tile(&tile);
+
+                let finished = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                if let Some(progress) = &self.progress {
+                    progress(finished, tile_count);
+                }
+
+                (tile, pixels)
+            })
+            .collect();
+
+        let mut image = Image::new(settings.width, settings.height);
+        for (tile, pixels) in rendered {
+            for (index, color) in pixels.into_iter().enumerate() {
+                image.set_pixel(
+                    tile.x + index % tile.width,
+                    tile.y + index / tile.width,
+                    color,
+                );
+            }
+        }
+        image
+    }
+
+    fn render_tile(&self, tile: &Tile) -> Vec<Color> {
+        let settings = &self.settings;
+        let mut pixels = Vec::with_capacity(tile.width * tile.height);
+
+        for y in tile.y..tile.y + tile.height {
+            // Image rows go from top to bottom, camera coordinates from bottom to top
+            let j = settings.height - 1 - y;
+            for i in tile.x..tile.x + tile.width {
+                let mut pixel_color = Color::new(0.0, 0.0, 0.0);
+                for _ in 0..settings.samples_per_pixel {
+                    let u = ((i as f64) + random::random()) / ((settings.width - 1) as f64);
+                    let v = ((j as f64) + random::random()) / ((settings.height - 1) as f64);
+
+                    let ray = self.camera.get_ray(u, v);
+                    pixel_color += if settings.spectral {
+                        self.spectral_ray_xyz(ray, settings.max_depth)
+                    } else {
+                        self.ray_color(&ray, settings.max_depth)
+                    };
+                }
+                pixel_color /= settings.samples_per_pixel as f64;
+                if settings.spectral {
+                    pixel_color = spectrum::xyz_to_rgb(pixel_color);
+                }
+                pixels.push(pixel_color);
+            }
+        }
+        pixels
+    }
+
+    fn ray_color(&self, ray: &Ray, depth: u64) -> Color {
+        self.trace(ray, depth, None, None)
+    }
+
+    // CIE XYZ of the light along a camera ray, from a path of freshly sampled wavelengths
+    fn spectral_ray_xyz(&self, ray: Ray, depth: u64) -> Vec3 {
+        let wavelengths = Wavelengths::sample(random::random());
+        let ray = ray.with_wavelength(Some(wavelengths.hero()));
+        let radiance = self.trace(&ray, depth, None, Some(wavelengths));
+        wavelengths.to_xyz(radiance)
+    }
+
+    // `scatter_pdf` is the density with which the previous bounce picked `ray`, or None when
+    // the lights could not have been sampled instead: for camera rays and mirrors.
+    // With `wavelengths`, the result holds the radiance at each of them instead of RGB.
+    fn trace(
+        &self,
+        ray: &Ray,
+        depth: u64,
+        scatter_pdf: Option<f64>,
+        wavelengths: Option<Wavelengths>,
+    ) -> Color {
+        if depth == 0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+
+        let Some(record) = self.world.hit(ray, 0.001, f64::INFINITY) else {
+            let radiance = spectral(self.environment.radiance(ray.direction()), &wavelengths);
+            return match scatter_pdf {
+                Some(scatter_pdf) => {
+                    let light_pdf = self.environment.pdf(ray.direction());
+                    power_heuristic(scatter_pdf, light_pdf) * radiance
+                }
+                None => radiance,
+            };
+        };
+
+        let mut emitted = spectral(record.material.emitted(ray, &record), &wavelengths);
+        if let Some(scatter_pdf) = scatter_pdf {
+            if !self.lights.is_empty() && !emitted.near_zero() {
+                let light_pdf = self.light_pdf(ray.origin(), ray.direction());
+                emitted *= power_heuristic(scatter_pdf, light_pdf);
+            }
+        }
+        let Some((attenuation, scattered)) = record.material.scatter(ray, &record) else {
+            return emitted;
+        };
+        let attenuation = spectral(attenuation, &wavelengths);
+        let scattered = scattered.with_wavelength(ray.wavelength());
+
+        // A direction that depends on the wavelength was only picked for the hero wavelength
+        let mut wavelengths = wavelengths;
+        let mut secondary = Color::new(1.0, 1.0, 1.0);
+        if let Some(wavelengths) = &mut wavelengths {
+            if record.material.is_dispersive() {
+                secondary = wavelengths.terminate_secondary();
+            }
+        }
+
+        let scatter_pdf = record.material.pdf(ray, &record, scattered.direction());
+        let scattered_light = if scatter_pdf > 0.0 {
+            self.sample_lights(ray, &record, &wavelengths)
+                + self.sample_punctual_lights(ray, &record, &wavelengths)
+                + self.sample_environment(ray, &record, &wavelengths)
+                + attenuation * self.trace(&scattered, depth - 1, Some(scatter_pdf), wavelengths)
+        } else {
+            attenuation * self.trace(&scattered, depth - 1, None, wavelengths)
+        };
+        emitted + secondary * scattered_light
+    }
+
+    // Direct light from one direction picked by the environment's own distribution,
+    // weighted against the chance that the material would have scattered that way
+    fn sample_environment(
+        &self,
+        ray: &Ray,
+        record: &HitRecord,
+        wavelengths: &Option<Wavelengths>,
+    ) -> Color {
+        let black = Color::new(0.0, 0.0, 0.0);
+        let Some((direction, light_pdf)) = self.environment.sample() else {
+            return black;
+        };
+        let bsdf = record.material.eval(ray, record, direction);
+        if bsdf.near_zero() {
+            return black;
+        }
+        let shadow_ray = Ray::new(record.point, direction);
+        if self.world.hit(&shadow_ray, 0.001, f64::INFINITY).is_some() {
+            return black;
+        }
+
+        let scatter_pdf = record.material.pdf(ray, record, direction);
+        power_heuristic(light_pdf, scatter_pdf) / light_pdf
+            * spectral(bsdf, wavelengths)
+            * spectral(self.environment.radiance(direction), wavelengths)
+    }
+
+    // Direct light from a point on one of the lights, picked uniformly, weighted against the
+    // chance that the material would have scattered towards it
+    fn sample_lights(
+        &self,
+        ray: &Ray,
+        record: &HitRecord,
+        wavelengths: &Option<Wavelengths>,
+    ) -> Color {
+        let black = Color::new(0.0, 0.0, 0.0);
+        if self.lights.is_empty() {
+            return black;
+        }
+        let index =
+            ((random::random() * self.lights.len() as f64) as usize).min(self.lights.len() - 1);
+        let direction = self.lights[index].random(record.point);
+        let light_pdf = self.light_pdf(record.point, direction);
+        if light_pdf <= 0.0 {
+            return black;
+        }
+        let bsdf = record.material.eval(ray, record, direction);
+        if bsdf.near_zero() {
+            return black;
+        }
+
+        // Whatever the shadow ray hits first is what the light sample sees. Another light
+        // in front is fine, as `light_pdf` accounts for every light along the direction.
+        let shadow_ray = Ray::new(record.point, direction);
+        let Some(light_record) = self.world.hit(&shadow_ray, 0.001, f64::INFINITY) else {
+            return black;
+        };
+        let emitted = light_record.material.emitted(&shadow_ray, &light_record);
+
+        let scatter_pdf = record.material.pdf(ray, record, direction);
+        power_heuristic(light_pdf, scatter_pdf) / light_pdf
+            * spectral(bsdf, wavelengths)
+            * spectral(emitted, wavelengths)
+    }
+
+    // Lights without extent cannot be hit by scattered rays, so there is nothing to weigh
+    // their samples against
+    fn sample_punctual_lights(
+        &self,
+        ray: &Ray,
+        record: &HitRecord,
+        wavelengths: &Option<Wavelengths>,
+    ) -> Color {
+        let mut color = Color::new(0.0, 0.0, 0.0);
+        for light in &self.punctual_lights {
+            let Some(sample) = light.sample(record.point) else {
+                continue;
+            };
+            let bsdf = record.material.eval(ray, record, sample.direction);
+            if bsdf.near_zero() {
+                continue;
+            }
+            let shadow_ray = Ray::new(record.point, sample.direction);
+            if self
+                .world
+                .hit(&shadow_ray, 0.001, sample.distance - 0.001)
+                .is_none()
+            {
+                color += spectral(bsdf, wavelengths) * spectral(sample.irradiance, wavelengths);
+            }
+        }
+        color
+    }
+
+    // Density of `sample_lights` picking `direction` from `origin`
+    fn light_pdf(&self, origin: Point3, direction: Vec3) -> f64 {
+        let total: f64 = self
+            .lights
+            .iter()
+            .map(|light| light.pdf_value(origin, direction))
+            .sum();
+        total / self.lights.len() as f64
+    }
+}
+
+// Seed of the generator for tile `index`. Hashing both keeps the streams of different seeds
+// apart; with seed + index, tile i + 1 would repeat the noise that seed + 1 gives tile i.
+fn tile_seed(seed: u64, index: usize) -> u64 {
+    splitmix64(splitmix64(seed) ^ index as u64)
+}
+
+// One step of the SplitMix64 generator, a cheap hash that spreads nearby inputs apart
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// In spectral rendering, RGB colors from materials and lights become their values at the
+// path's wavelengths. Upsampling products like the BSDF weights instead of every factor is
+// exact for anything linear in a single color, and a close approximation otherwise.
+fn spectral(color: Color, wavelengths: &Option<Wavelengths>) -> Color {
+    match wavelengths {
+        Some(wavelengths) => wavelengths.upsample(color),
+        None => color,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::LinearBvh;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::sphere::Sphere;
+    use crate::triangle::{Face, TriangleMesh};
+
+    const SAMPLES: usize = 200_000;
+
+    // A light's density has to integrate to one over all directions, and weighting its
+    // samples by it has to give the same integral as directions drawn uniformly
+    fn assert_pdf_matches_samples(light: &dyn Hit, origin: Point3) {
+        let solid_angle = 4.0 * std::f64::consts::PI;
+        // Any smooth function of the direction will do
+        let f = |direction: Vec3| {
+            let direction = direction.normalized();
+            1.0 + direction.x() + direction.y() * direction.y()
+        };
+
+        let (mut integral, mut uniform) = (0.0, 0.0);
+        for _ in 0..SAMPLES {
+            let direction = Vec3::random_in_unit_sphere().normalized();
+            let pdf = light.pdf_value(origin, direction);
+            integral += pdf;
+            if pdf > 0.0 {
+                uniform += f(direction);
+            }
+        }
+        let integral = integral * solid_angle / SAMPLES as f64;
+        assert!(
+            (integral - 1.0).abs() < 0.02,
+            "pdf integrates to {}",
+            integral
+        );
+
+        let mut sampled = 0.0;
+        for _ in 0..SAMPLES {
+            let direction = light.random(origin);
+            let pdf = light.pdf_value(origin, direction);
+            assert!(pdf > 0.0, "a sample has zero density");
+            sampled += f(direction) / pdf;
+        }
+        let uniform = uniform * solid_angle / SAMPLES as f64;
+        let sampled = sampled / SAMPLES as f64;
+        assert!(
+            (sampled / uniform - 1.0).abs() < 0.02,
+            "{} from samples of the light, {} from uniform directions",
+            sampled,
+            uniform
+        );
+    }
+
+    fn light_material() -> Arc<DiffuseLight> {
+        Arc::new(DiffuseLight::new(Color::new(4.0, 4.0, 4.0)))
+    }
+
+    #[test]
+    fn sphere_pdf_matches_its_samples() {
+        random::seed(1);
+        let sphere = Sphere::new(Point3::new(0.5, 2.0, -1.0), 1.0, light_material());
+        assert_pdf_matches_samples(&sphere, Point3::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn mesh_triangle_pdf_matches_its_samples() {
+        random::seed(2);
+        let mesh = TriangleMesh::new(
+            vec![
+                Point3::new(-1.0, 1.0, -1.0),
+                Point3::new(1.5, 1.2, -1.0),
+                Point3::new(0.0, 1.0, 1.0),
+            ],
+            Vec::new(),
+            Vec::new(),
+            vec![Face {
+                positions: [0, 1, 2],
+                normals: None,
+                uvs: None,
+            }],
+            light_material(),
+        )
+        .unwrap();
+        let triangles = Arc::new(mesh).triangles();
+        assert_pdf_matches_samples(triangles[0].as_ref(), Point3::new(0.2, 0.0, 0.1));
+    }
+
+    #[test]
+    fn adjacent_seeds_give_unrelated_tiles() {
+        let mut seeds = std::collections::HashSet::new();
+        for seed in 0..64 {
+            for index in 0..64 {
+                assert!(seeds.insert(tile_seed(seed, index)));
+            }
+        }
+    }
+
+    fn mean(image: &Image) -> f64 {
+        let total: f64 = image.pixels().iter().map(|color| color.luminance()).sum();
+        total / image.pixels().len() as f64
+    }
+
+    #[test]
+    fn light_sampling_converges_to_the_same_image() {
+        let floor: Arc<dyn Hit> = Arc::new(Sphere::new(
+            Point3::new(0.0, -100.0, 0.0),
+            100.0,
+            Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5))),
+        ));
+        let light: Arc<dyn Hit> = Arc::new(Sphere::new(
+            Point3::new(0.0, 1.5, 0.0),
+            0.5,
+            light_material(),
+        ));
+        let world: Arc<dyn Hit> = Arc::new(LinearBvh::new(vec![floor, light.clone()]));
+        let camera = Camera::new(
+            Point3::new(0.0, 1.0, 4.0),
+            Point3::new(0.0, 0.5, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+            0.0,
+            4.0,
+        );
+        let settings = RenderSettings {
+            width: 32,
+            height: 32,
+            samples_per_pixel: 64,
+            max_depth: 4,
+            seed: 5,
+            spectral: false,
+        };
+        let render = |lights: Vec<Arc<dyn Hit>>| {
+            let renderer = Renderer::new(camera, world.clone(), settings.clone())
+                .unwrap()
+                .with_environment(Arc::new(SolidColor::new(Color::new(0.0, 0.0, 0.0))))
+                .with_lights(lights);
+            mean(&renderer.render())
+        };
+
+        // Only scattered rays find the light without it in the list of lights
+        let scattered_only = render(Vec::new());
+        let weighted = render(vec![light]);
+        assert!(
+            (weighted / scattered_only - 1.0).abs() < 0.05,
+            "{} with light sampling, {} without",
+            weighted,
+            scattered_only
+        );
+    }
+}
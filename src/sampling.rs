@@ -0,0 +1,142 @@
+// sampling.rs
+use super::vec::Vec3;
+
+// Piecewise-constant distribution over the indices of a list of non-negative weights
+pub struct Distribution {
+    // cdf[i] is the probability of picking an index below i, cdf[len] == 1
+    cdf: Vec<f64>,
+    total: f64,
+}
+
+impl Distribution {
+    pub fn new(weights: &[f64]) -> Distribution {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        let mut total = 0.0;
+        cdf.push(0.0);
+        for &weight in weights {
+            total += weight;
+            cdf.push(total);
+        }
+        if total > 0.0 {
+            for value in &mut cdf {
+                *value /= total;
+            }
+        }
+        Distribution { cdf, total }
+    }
+
+    // Sum of the weights; nothing can be sampled when it is zero
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    pub fn len(&self) -> usize {
+        self.cdf.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Maps `u` in [0, 1) to an index, returning it with its probability
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        // The last entry with cdf <= u, skipping indices of zero weight
+        let index = self
+            .cdf
+            .partition_point(|&value| value <= u)
+            .saturating_sub(1);
+        let index = index.min(self.len() - 1);
+        (index, self.probability(index))
+    }
+
+    pub fn probability(&self, index: usize) -> f64 {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+}
+
+// Weight of a sample from a strategy with density `pdf` when another strategy with density
+// `other_pdf` could have produced it too (Veach's power heuristic with exponent 2)
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (pdf, other_pdf) = (pdf * pdf, other_pdf * other_pdf);
+    if pdf + other_pdf == 0.0 {
+        0.0
+    } else {
+        pdf / (pdf + other_pdf)
+    }
+}
+
+// Two unit vectors that complete the unit vector `normal` to a right-handed orthonormal basis
+// (Duff et al., "Building an Orthonormal Basis, Revisited")
+pub fn tangent_frame(normal: Vec3) -> (Vec3, Vec3) {
+    let sign = 1.0_f64.copysign(normal.z());
+    let a = -1.0 / (sign + normal.z());
+    let b = normal.x() * normal.y() * a;
+    (
+        Vec3::new(
+            1.0 + sign * normal.x() * normal.x() * a,
+            sign * b,
+            -sign * normal.x(),
+        ),
+        Vec3::new(b, sign + normal.y() * normal.y() * a, -normal.y()),
+    )
+}
+
+// Orthonormal basis around a normal, for working in a local frame with the normal along +z
+#[derive(Clone, Copy)]
+pub struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    pub fn new(normal: Vec3) -> Frame {
+        let (tangent, bitangent) = tangent_frame(normal);
+        Frame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, v: Vec3) -> Vec3 {
+        Vec3::new(
+            v.dot(self.tangent),
+            v.dot(self.bitangent),
+            v.dot(self.normal),
+        )
+    }
+
+    pub fn to_world(&self, v: Vec3) -> Vec3 {
+        v.x() * self.tangent + v.y() * self.bitangent + v.z() * self.normal
+    }
+}
+
+// Cosine-weighted direction on the hemisphere around +z, with density cos(theta) / pi
+pub fn cosine_hemisphere(u1: f64, u2: f64) -> Vec3 {
+    let radius = u1.sqrt();
+    let phi = 2.0 * std::f64::consts::PI * u2;
+    Vec3::new(
+        radius * phi.cos(),
+        radius * phi.sin(),
+        (1.0 - u1).max(0.0).sqrt(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distributions_pick_indices_by_weight() {
+        let distribution = Distribution::new(&[1.0, 0.0, 3.0, 0.0]);
+        assert_eq!(distribution.total(), 4.0);
+        assert_eq!(distribution.sample(0.0), (0, 0.25));
+        assert_eq!(distribution.sample(0.2499), (0, 0.25));
+        // Indices without weight are never picked, not even at their cdf values
+        assert_eq!(distribution.sample(0.25), (2, 0.75));
+        assert_eq!(distribution.sample(0.9999), (2, 0.75));
+        assert_eq!(distribution.probability(1), 0.0);
+        assert_eq!(distribution.probability(3), 0.0);
+    }
+}
@@ -0,0 +1,1474 @@
+// scene.rs
+use super::camera::Camera;
+use super::environment::{Environment, EquirectangularMap, Gradient, SolidColor};
+use super::hit::{Hit, World};
+use super::input;
+use super::light::{DirectionalLight, Falloff, Light, PointLight, SpotLight};
+use super::material::{
+    Dielectric, DiffuseLight, Lambertian, Metal, Microfacet, NormalMapped, Perturbation,
+    Principled, Scatter,
+};
+use super::obj::{self, ObjError};
+use super::render::RenderSettings;
+use super::spectrum::{RefractiveIndex, MAX_WAVELENGTH, MIN_WAVELENGTH};
+use super::sphere::Sphere;
+use super::texture::{
+    self, Checker, ImageTexture, NoiseTexture, Scaled, Texture, TurbulenceTexture, WrapMode,
+};
+use super::tlas::Tlas;
+use super::transform::{Matrix4, Transformed};
+use super::triangle::{Face, Triangle, TriangleMesh};
+use super::vec::{Point3, Vec3};
+
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub struct Scene {
+    pub camera: Camera,
+    pub world: World,
+    pub settings: RenderSettings,
+    // Radiance of rays leaving the scene, the sky gradient unless the scene picks another
+    pub environment: Arc<dyn Environment>,
+    // Objects with an emissive material, also part of `world`, for sampling them directly
+    pub lights: Vec<Arc<dyn Hit>>,
+    pub punctual_lights: Vec<Arc<dyn Light>>,
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    // A value that parsed correctly but cannot be used, e.g. an unknown material name
+    Invalid {
+        path: PathBuf,
+        entry: String,
+        key: &'static str,
+        message: String,
+    },
+    Mesh {
+        path: PathBuf,
+        entry: String,
+        source: ObjError,
+    },
+    Image {
+        path: PathBuf,
+        entry: String,
+        file: PathBuf,
+        source: io::Error,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            SceneError::Parse { path, source } => write!(f, "{}: {}", path.display(), source),
+            SceneError::Invalid {
+                path,
+                entry,
+                key,
+                message,
+            } => write!(f, "{}: {}.{}: {}", path.display(), entry, key, message),
+            SceneError::Mesh {
+                path,
+                entry,
+                source,
+            } => write!(f, "{}: {}: {}", path.display(), entry, source),
+            SceneError::Image {
+                path,
+                entry,
+                file,
+                source,
+            } => write!(
+                f,
+                "{}: {}: {}: {}",
+                path.display(),
+                entry,
+                file.display(),
+                source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SceneError::Io { source, .. } => Some(source),
+            SceneError::Parse { source, .. } => Some(source),
+            SceneError::Mesh { source, .. } => Some(source),
+            SceneError::Image { source, .. } => Some(source),
+            SceneError::Invalid { .. } => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDescription {
+    render: RenderDescription,
+    camera: CameraDescription,
+    environment: Option<EnvironmentDescription>,
+    // Older spelling of a solid color environment
+    background: Option<[f64; 3]>,
+    #[serde(default)]
+    textures: HashMap<String, TextureDescription>,
+    #[serde(default)]
+    materials: HashMap<String, MaterialDescription>,
+    #[serde(default)]
+    objects: Vec<ObjectDescription>,
+    #[serde(default)]
+    lights: Vec<LightDescription>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RenderDescription {
+    width: usize,
+    height: usize,
+    samples_per_pixel: u64,
+    max_depth: u64,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    spectral: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDescription {
+    look_from: [f64; 3],
+    look_at: [f64; 3],
+    #[serde(default = "default_up")]
+    up: [f64; 3],
+    vertical_fov: f64,
+    #[serde(default)]
+    aperture: f64,
+    focus_distance: Option<f64>,
+}
+
+fn default_up() -> [f64; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum EnvironmentDescription {
+    Solid {
+        color: [f64; 3],
+    },
+    Gradient {
+        #[serde(default = "default_gradient_bottom")]
+        bottom: [f64; 3],
+        #[serde(default = "default_gradient_top")]
+        top: [f64; 3],
+    },
+    // Equirectangular .hdr or PNG map; `rotation` in degrees about the up axis
+    Image {
+        file: PathBuf,
+        #[serde(default)]
+        rotation: f64,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
+}
+
+fn default_gradient_bottom() -> [f64; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_gradient_top() -> [f64; 3] {
+    [0.5, 0.7, 1.0]
+}
+
+// Either a constant color or the name of an entry in `textures`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColorParameter {
+    Constant([f64; 3]),
+    Texture(String),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum TextureDescription {
+    // Cubes of edge length `scale` in world space
+    Checker {
+        even: [f64; 3],
+        odd: [f64; 3],
+        #[serde(default = "default_scale")]
+        scale: f64,
+    },
+    // PNG or PPM image, mapped with the surface coordinates of the object
+    Image {
+        file: PathBuf,
+        #[serde(default)]
+        wrap: WrapDescription,
+        // Data such as normal or height maps rather than sRGB colors
+        #[serde(default)]
+        linear: bool,
+    },
+    // Perlin noise at a frequency of `scale` per unit, the same for the same seed
+    Noise {
+        #[serde(default = "default_white")]
+        color: [f64; 3],
+        #[serde(default = "default_scale")]
+        scale: f64,
+        #[serde(default)]
+        seed: u64,
+    },
+    Turbulence {
+        #[serde(default = "default_white")]
+        color: [f64; 3],
+        #[serde(default = "default_scale")]
+        scale: f64,
+        #[serde(default = "default_octaves")]
+        octaves: u32,
+        #[serde(default)]
+        seed: u64,
+    },
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum WrapDescription {
+    #[default]
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl From<WrapDescription> for WrapMode {
+    fn from(wrap: WrapDescription) -> WrapMode {
+        match wrap {
+            WrapDescription::Repeat => WrapMode::Repeat,
+            WrapDescription::Clamp => WrapMode::Clamp,
+            WrapDescription::Mirror => WrapMode::Mirror,
+        }
+    }
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+fn default_white() -> [f64; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_octaves() -> u32 {
+    7
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialDescription {
+    Lambertian {
+        albedo: ColorParameter,
+    },
+    Metal {
+        albedo: ColorParameter,
+        fuzz: f64,
+    },
+    // Glass; `tint` is the color white light takes on after `tint_distance` inside it. Tinted
+    // objects must be closed and must not overlap or sit inside other glass.
+    Dielectric {
+        refraction_index: RefractiveIndexParameter,
+        #[serde(default)]
+        roughness: f64,
+        #[serde(default = "default_white")]
+        tint: [f64; 3],
+        #[serde(default = "default_intensity")]
+        tint_distance: f64,
+    },
+    // GGX metal/roughness surface, from a plastic-like dielectric to a pure metal
+    Microfacet {
+        base_color: ColorParameter,
+        roughness: f64,
+        #[serde(default)]
+        metallic: f64,
+    },
+    // Disney-style uber-material; everything but the base color is optional
+    Principled {
+        base_color: ColorParameter,
+        #[serde(default)]
+        metallic: f64,
+        #[serde(default = "default_roughness")]
+        roughness: f64,
+        #[serde(default = "default_specular")]
+        specular: f64,
+        #[serde(default)]
+        specular_tint: f64,
+        #[serde(default)]
+        sheen: f64,
+        #[serde(default)]
+        clearcoat: f64,
+        #[serde(default = "default_clearcoat_gloss")]
+        clearcoat_gloss: f64,
+        #[serde(default)]
+        transmission: f64,
+        #[serde(default = "default_ior")]
+        ior: f64,
+    },
+    DiffuseLight {
+        color: ColorParameter,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
+    // Another material with its shading normals taken from a tangent-space normal map
+    NormalMapped {
+        material: String,
+        normal_map: String,
+    },
+    // Another material with its shading normals bent by a height map
+    BumpMapped {
+        material: String,
+        height: String,
+        #[serde(default = "default_scale")]
+        scale: f64,
+    },
+}
+
+// Either a constant index of refraction or one that depends on the wavelength, which only
+// makes a difference in spectral rendering
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum RefractiveIndexParameter {
+    Constant(f64),
+    Dispersive(DispersionDescription),
+}
+
+// Wavelengths in micrometers, as in glass catalogs
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum DispersionDescription {
+    Cauchy { a: f64, b: f64 },
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+fn default_intensity() -> f64 {
+    1.0
+}
+
+fn default_roughness() -> f64 {
+    0.5
+}
+
+fn default_specular() -> f64 {
+    0.5
+}
+
+fn default_clearcoat_gloss() -> f64 {
+    1.0
+}
+
+fn default_ior() -> f64 {
+    1.5
+}
+
+// Lights outside the world; `direction` is always the direction the light shines in
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum LightDescription {
+    Point {
+        position: [f64; 3],
+        color: [f64; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+        #[serde(default)]
+        falloff: FalloffDescription,
+    },
+    // Cone angles in degrees from `direction`
+    Spot {
+        position: [f64; 3],
+        direction: [f64; 3],
+        color: [f64; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+        #[serde(default)]
+        falloff: FalloffDescription,
+        inner_angle: f64,
+        outer_angle: f64,
+    },
+    // Angular diameter in degrees, zero for perfectly sharp shadows
+    Directional {
+        direction: [f64; 3],
+        color: [f64; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+        #[serde(default)]
+        angular_diameter: f64,
+    },
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum FalloffDescription {
+    None,
+    Linear,
+    #[default]
+    Quadratic,
+}
+
+impl From<FalloffDescription> for Falloff {
+    fn from(falloff: FalloffDescription) -> Falloff {
+        match falloff {
+            FalloffDescription::None => Falloff::None,
+            FalloffDescription::Linear => Falloff::Linear,
+            FalloffDescription::Quadratic => Falloff::Quadratic,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ObjectDescription {
+    Sphere {
+        center: [f64; 3],
+        radius: f64,
+        material: String,
+    },
+    Triangle {
+        vertices: [[f64; 3]; 3],
+        material: String,
+    },
+    // Parallelogram spanned by the edges `u` and `v` from `corner`
+    Quad {
+        corner: [f64; 3],
+        u: [f64; 3],
+        v: [f64; 3],
+        material: String,
+    },
+    // Axis-aligned box between two opposite corners
+    Box {
+        min: [f64; 3],
+        max: [f64; 3],
+        material: String,
+    },
+    // Materials from the OBJ's own MTL libraries take precedence over `material`
+    Mesh {
+        file: PathBuf,
+        material: String,
+    },
+    // Another object, scaled first, then rotated about the x, y and z axes in that order and
+    // finally translated. Instances of the same mesh share one hierarchy.
+    Instance {
+        object: Box<ObjectDescription>,
+        #[serde(default)]
+        translate: [f64; 3],
+        // Angles in degrees
+        #[serde(default)]
+        rotate: [f64; 3],
+        #[serde(default = "default_instance_scale")]
+        scale: ScaleParameter,
+    },
+}
+
+// Either the same scale factor along every axis or one per axis
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum ScaleParameter {
+    Uniform(f64),
+    PerAxis([f64; 3]),
+}
+
+fn default_instance_scale() -> ScaleParameter {
+    ScaleParameter::Uniform(1.0)
+}
+
+impl ObjectDescription {
+    // Material of the object, or of the object it instances
+    fn material(&self) -> &str {
+        match self {
+            ObjectDescription::Sphere { material, .. }
+            | ObjectDescription::Triangle { material, .. }
+            | ObjectDescription::Quad { material, .. }
+            | ObjectDescription::Box { material, .. }
+            | ObjectDescription::Mesh { material, .. } => material,
+            ObjectDescription::Instance { object, .. } => object.material(),
+        }
+    }
+}
+
+fn vec3(value: [f64; 3]) -> Vec3 {
+    Vec3::new(value[0], value[1], value[2])
+}
+
+// Loads a TOML scene description. Relative mesh and image paths are resolved against the scene file.
+pub fn load(path: &Path) -> Result<Scene, SceneError> {
+    let source = fs::read_to_string(path).map_err(|source| SceneError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let description: SceneDescription =
+        toml::from_str(&source).map_err(|source| SceneError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+    let invalid = |entry: &str, key: &'static str, message: String| SceneError::Invalid {
+        path: path.to_path_buf(),
+        entry: entry.to_string(),
+        key,
+        message,
+    };
+
+    let render = &description.render;
+    if render.width < 2 {
+        return Err(invalid("render", "width", "must be at least 2".to_string()));
+    }
+    if render.height < 2 {
+        return Err(invalid(
+            "render",
+            "height",
+            "must be at least 2".to_string(),
+        ));
+    }
+    if render.samples_per_pixel == 0 {
+        return Err(invalid(
+            "render",
+            "samples_per_pixel",
+            "must be positive".to_string(),
+        ));
+    }
+    let settings = RenderSettings {
+        width: render.width,
+        height: render.height,
+        samples_per_pixel: render.samples_per_pixel,
+        max_depth: render.max_depth,
+        seed: render.seed,
+        spectral: render.spectral,
+    };
+
+    let camera = &description.camera;
+    let look_from = vec3(camera.look_from);
+    let look_at = vec3(camera.look_at);
+    let up = vec3(camera.up);
+    if !(camera.vertical_fov > 0.0 && camera.vertical_fov < 180.0) {
+        return Err(invalid(
+            "camera",
+            "vertical_fov",
+            "must be in (0, 180) degrees".to_string(),
+        ));
+    }
+    let view = look_at - look_from;
+    if view.length() == 0.0 {
+        return Err(invalid(
+            "camera",
+            "look_at",
+            "must differ from look_from".to_string(),
+        ));
+    }
+    // Also catches a zero up vector
+    if view.cross(up).length() <= 1.0e-9 * view.length() * up.length() {
+        return Err(invalid(
+            "camera",
+            "up",
+            "must not be parallel to the view direction".to_string(),
+        ));
+    }
+    let focus_distance = camera
+        .focus_distance
+        .unwrap_or_else(|| (look_from - look_at).length());
+    let camera = Camera::new(
+        look_from,
+        look_at,
+        up,
+        camera.vertical_fov,
+        (settings.width as f64) / (settings.height as f64),
+        camera.aperture,
+        focus_distance,
+    );
+
+    let environment = match (&description.environment, description.background) {
+        (Some(_), Some(_)) => {
+            return Err(invalid(
+                "scene",
+                "background",
+                "cannot be combined with [environment]".to_string(),
+            ))
+        }
+        (None, Some(color)) => Some(EnvironmentDescription::Solid { color }),
+        (environment, None) => environment.clone(),
+    };
+    let environment: Arc<dyn Environment> = match &environment {
+        None => Arc::new(Gradient::default()),
+        Some(EnvironmentDescription::Solid { color }) => Arc::new(SolidColor::new(vec3(*color))),
+        Some(EnvironmentDescription::Gradient { bottom, top }) => {
+            Arc::new(Gradient::new(vec3(*bottom), vec3(*top)))
+        }
+        Some(EnvironmentDescription::Image {
+            file,
+            rotation,
+            intensity,
+        }) => {
+            let file = path.parent().unwrap_or_else(|| Path::new("")).join(file);
+            let image = input::read(&file).map_err(|source| SceneError::Image {
+                path: path.to_path_buf(),
+                entry: "environment".to_string(),
+                file: file.clone(),
+                source,
+            })?;
+            Arc::new(EquirectangularMap::new(image, *rotation, *intensity))
+        }
+    };
+
+    let mut textures: HashMap<&str, Arc<dyn Texture>> = HashMap::new();
+    for (name, texture) in &description.textures {
+        let entry = format!("textures.{}", name);
+        let positive = |key: &'static str, value: f64| {
+            if value > 0.0 {
+                Ok(value)
+            } else {
+                Err(invalid(&entry, key, "must be positive".to_string()))
+            }
+        };
+        let texture: Arc<dyn Texture> = match texture {
+            TextureDescription::Checker { even, odd, scale } => Arc::new(Checker::new(
+                Arc::new(texture::SolidColor::new(vec3(*even))),
+                Arc::new(texture::SolidColor::new(vec3(*odd))),
+                positive("scale", *scale)?,
+            )),
+            TextureDescription::Image { file, wrap, linear } => {
+                let file = path.parent().unwrap_or_else(|| Path::new("")).join(file);
+                let image = if *linear {
+                    input::read_linear(&file)
+                } else {
+                    input::read(&file)
+                };
+                let image = image.map_err(|source| SceneError::Image {
+                    path: path.to_path_buf(),
+                    entry: entry.clone(),
+                    file: file.clone(),
+                    source,
+                })?;
+                Arc::new(ImageTexture::new(image, (*wrap).into()))
+            }
+            TextureDescription::Noise { color, scale, seed } => Arc::new(NoiseTexture::new(
+                vec3(*color),
+                positive("scale", *scale)?,
+                *seed,
+            )),
+            TextureDescription::Turbulence {
+                color,
+                scale,
+                octaves,
+                seed,
+            } => Arc::new(TurbulenceTexture::new(
+                vec3(*color),
+                positive("scale", *scale)?,
+                *octaves,
+                *seed,
+            )),
+        };
+        textures.insert(name, texture);
+    }
+
+    let mut materials: HashMap<&str, Arc<dyn Scatter>> = HashMap::new();
+    let mut emissive = HashSet::new();
+    // Normal and bump mapped materials wrap other entries, so they wait for those to be built
+    let mut pending: Vec<(&String, &MaterialDescription)> = description.materials.iter().collect();
+    // In order of their names, so that errors name the same material on every run
+    pending.sort_by_key(|&(name, _)| name);
+    while !pending.is_empty() {
+        let mut deferred = Vec::new();
+        for &(name, material) in &pending {
+            let entry = format!("materials.{}", name);
+            let named_texture = |key: &'static str, name: &str| {
+                textures
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| invalid(&entry, key, format!("unknown texture `{}`", name)))
+            };
+            let texture = |key: &'static str, parameter: &ColorParameter| match parameter {
+                ColorParameter::Constant(color) => {
+                    Ok(Arc::new(texture::SolidColor::new(vec3(*color))) as Arc<dyn Texture>)
+                }
+                ColorParameter::Texture(name) => named_texture(key, name),
+            };
+
+            let base = match material {
+                MaterialDescription::NormalMapped { material: base, .. }
+                | MaterialDescription::BumpMapped { material: base, .. } => {
+                    match materials.get(base.as_str()) {
+                        Some(base) => Some(base.clone()),
+                        None => {
+                            deferred.push((name, material));
+                            continue;
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            let material: Arc<dyn Scatter> = match material {
+                MaterialDescription::Lambertian { albedo } => {
+                    Arc::new(Lambertian::textured(texture("albedo", albedo)?))
+                }
+                MaterialDescription::Metal { albedo, fuzz } => {
+                    if !(0.0..=1.0).contains(fuzz) {
+                        return Err(invalid(&entry, "fuzz", "must be in [0, 1]".to_string()));
+                    }
+                    Arc::new(Metal::textured(texture("albedo", albedo)?, *fuzz))
+                }
+                MaterialDescription::DiffuseLight { color, intensity } => {
+                    emissive.insert(name.as_str());
+                    Arc::new(DiffuseLight::textured(Arc::new(Scaled::new(
+                        texture("color", color)?,
+                        *intensity,
+                    ))))
+                }
+                &MaterialDescription::Dielectric {
+                    refraction_index,
+                    roughness,
+                    tint,
+                    tint_distance,
+                } => {
+                    let refraction_index = match refraction_index {
+                        RefractiveIndexParameter::Constant(index) => {
+                            RefractiveIndex::Constant(index)
+                        }
+                        RefractiveIndexParameter::Dispersive(DispersionDescription::Cauchy {
+                            a,
+                            b,
+                        }) => RefractiveIndex::Cauchy { a, b },
+                        RefractiveIndexParameter::Dispersive(
+                            DispersionDescription::Sellmeier { b, c },
+                        ) => {
+                            // Each term has a pole where λ² = c_i, which sampling the visible
+                            // range below could step over
+                            let micrometers_squared = |nm: f64| (nm * 1.0e-3).powi(2);
+                            let visible = micrometers_squared(MIN_WAVELENGTH)
+                                ..=micrometers_squared(MAX_WAVELENGTH);
+                            if c.iter().any(|c_i| visible.contains(c_i)) {
+                                return Err(invalid(
+                                    &entry,
+                                    "refraction_index",
+                                    format!(
+                                        "c must lie outside [{:.4}, {:.4}] µm², the visible range",
+                                        visible.start(),
+                                        visible.end()
+                                    ),
+                                ));
+                            }
+                            RefractiveIndex::Sellmeier { b, c }
+                        }
+                    };
+                    // Every 10nm across the visible range
+                    let visible = (0..=47).map(|i| MIN_WAVELENGTH + 10.0 * i as f64);
+                    if visible
+                        .map(|wavelength| refraction_index.at(wavelength))
+                        .any(|index| !(index.is_finite() && index > 0.0))
+                    {
+                        return Err(invalid(
+                            &entry,
+                            "refraction_index",
+                            "must be positive across the visible spectrum".to_string(),
+                        ));
+                    }
+                    if !(0.0..=1.0).contains(&roughness) {
+                        return Err(invalid(
+                            &entry,
+                            "roughness",
+                            "must be in [0, 1]".to_string(),
+                        ));
+                    }
+                    if tint
+                        .iter()
+                        .any(|&channel| !(channel > 0.0 && channel <= 1.0))
+                    {
+                        return Err(invalid(&entry, "tint", "must be in (0, 1]".to_string()));
+                    }
+                    if tint_distance <= 0.0 {
+                        return Err(invalid(
+                            &entry,
+                            "tint_distance",
+                            "must be positive".to_string(),
+                        ));
+                    }
+                    Arc::new(
+                        Dielectric::dispersive(refraction_index)
+                            .with_roughness(roughness)
+                            .with_absorption(vec3(tint), tint_distance),
+                    )
+                }
+                MaterialDescription::Microfacet {
+                    base_color,
+                    roughness,
+                    metallic,
+                } => {
+                    for (key, value) in [("roughness", roughness), ("metallic", metallic)] {
+                        if !(0.0..=1.0).contains(value) {
+                            return Err(invalid(&entry, key, "must be in [0, 1]".to_string()));
+                        }
+                    }
+                    Arc::new(Microfacet::textured(
+                        texture("base_color", base_color)?,
+                        *roughness,
+                        *metallic,
+                    ))
+                }
+                MaterialDescription::Principled {
+                    base_color,
+                    metallic,
+                    roughness,
+                    specular,
+                    specular_tint,
+                    sheen,
+                    clearcoat,
+                    clearcoat_gloss,
+                    transmission,
+                    ior,
+                } => {
+                    for (key, value) in [
+                        ("metallic", metallic),
+                        ("roughness", roughness),
+                        ("specular", specular),
+                        ("specular_tint", specular_tint),
+                        ("sheen", sheen),
+                        ("clearcoat", clearcoat),
+                        ("clearcoat_gloss", clearcoat_gloss),
+                        ("transmission", transmission),
+                    ] {
+                        if !(0.0..=1.0).contains(value) {
+                            return Err(invalid(&entry, key, "must be in [0, 1]".to_string()));
+                        }
+                    }
+                    if *ior <= 0.0 {
+                        return Err(invalid(&entry, "ior", "must be positive".to_string()));
+                    }
+                    Arc::new(
+                        Principled::textured(texture("base_color", base_color)?)
+                            .with_metallic(*metallic)
+                            .with_roughness(*roughness)
+                            .with_specular(*specular, *specular_tint)
+                            .with_sheen(*sheen)
+                            .with_clearcoat(*clearcoat, *clearcoat_gloss)
+                            .with_transmission(*transmission, *ior),
+                    )
+                }
+                MaterialDescription::NormalMapped {
+                    material,
+                    normal_map,
+                } => {
+                    if emissive.contains(material.as_str()) {
+                        emissive.insert(name.as_str());
+                    }
+                    Arc::new(NormalMapped::new(
+                        base.unwrap(),
+                        Perturbation::NormalMap(named_texture("normal_map", normal_map)?),
+                    ))
+                }
+                MaterialDescription::BumpMapped {
+                    material,
+                    height,
+                    scale,
+                } => {
+                    if emissive.contains(material.as_str()) {
+                        emissive.insert(name.as_str());
+                    }
+                    Arc::new(NormalMapped::new(
+                        base.unwrap(),
+                        Perturbation::BumpMap {
+                            height: named_texture("height", height)?,
+                            scale: *scale,
+                        },
+                    ))
+                }
+            };
+            materials.insert(name, material);
+        }
+
+        // Nothing was resolved in this pass, so the remaining bases never will be
+        if deferred.len() == pending.len() {
+            let (name, material) = deferred[0];
+            let base = match material {
+                MaterialDescription::NormalMapped { material, .. }
+                | MaterialDescription::BumpMapped { material, .. } => material,
+                _ => unreachable!("only wrapping materials are deferred"),
+            };
+            let message = if description.materials.contains_key(base.as_str()) {
+                format!("`{}` is part of a cycle of materials", base)
+            } else {
+                format!("unknown material `{}`", base)
+            };
+            return Err(invalid(&format!("materials.{}", name), "material", message));
+        }
+        pending = deferred;
+    }
+
+    let mut builder = ObjectBuilder {
+        path,
+        materials: &materials,
+        emissive: &emissive,
+        tlas: Tlas::new(),
+        instanced_meshes: HashMap::new(),
+        depth: 0,
+        next_id: 1,
+        objects: Vec::new(),
+        lights: Vec::new(),
+    };
+    for (index, object) in description.objects.iter().enumerate() {
+        builder.add(object, &format!("objects[{}]", index))?;
+    }
+    let ObjectBuilder {
+        mut tlas,
+        objects,
+        lights,
+        ..
+    } = builder;
+    // Shapes outside of instances make up one more bottom level, placed as they are
+    if let Some(blas) = tlas.add_blas_with_lights(objects, lights) {
+        tlas.add_instance(blas, Matrix4::identity());
+    }
+    tlas.rebuild();
+    let lights = tlas.lights().to_vec();
+
+    let mut punctual_lights: Vec<Arc<dyn Light>> = Vec::new();
+    for (index, light) in description.lights.iter().enumerate() {
+        let entry = format!("lights[{}]", index);
+        let light: Arc<dyn Light> = match *light {
+            LightDescription::Point {
+                position,
+                color,
+                intensity,
+                falloff,
+            } => Arc::new(PointLight::new(
+                vec3(position),
+                intensity * vec3(color),
+                falloff.into(),
+            )),
+            LightDescription::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                falloff,
+                inner_angle,
+                outer_angle,
+            } => {
+                if vec3(direction).near_zero() {
+                    return Err(invalid(&entry, "direction", "must not be zero".to_string()));
+                }
+                if !(0.0..=180.0).contains(&outer_angle) {
+                    return Err(invalid(
+                        &entry,
+                        "outer_angle",
+                        "must be between 0 and 180 degrees".to_string(),
+                    ));
+                }
+                if !(0.0..=outer_angle).contains(&inner_angle) {
+                    return Err(invalid(
+                        &entry,
+                        "inner_angle",
+                        "must be between 0 degrees and outer_angle".to_string(),
+                    ));
+                }
+                Arc::new(SpotLight::new(
+                    vec3(position),
+                    vec3(direction),
+                    intensity * vec3(color),
+                    falloff.into(),
+                    inner_angle,
+                    outer_angle,
+                ))
+            }
+            LightDescription::Directional {
+                direction,
+                color,
+                intensity,
+                angular_diameter,
+            } => {
+                if vec3(direction).near_zero() {
+                    return Err(invalid(&entry, "direction", "must not be zero".to_string()));
+                }
+                if !(0.0..180.0).contains(&angular_diameter) {
+                    return Err(invalid(
+                        &entry,
+                        "angular_diameter",
+                        "must be at least 0 and below 180 degrees".to_string(),
+                    ));
+                }
+                Arc::new(DirectionalLight::new(
+                    vec3(direction),
+                    intensity * vec3(color),
+                    angular_diameter,
+                ))
+            }
+        };
+        punctual_lights.push(light);
+    }
+
+    let world: World = if tlas.instance_count() == 0 {
+        World::new()
+    } else {
+        vec![Box::new(tlas)]
+    };
+
+    Ok(Scene {
+        camera,
+        world,
+        settings,
+        environment,
+        lights,
+        punctual_lights,
+    })
+}
+
+// Turns object descriptions into shapes, collecting the emissive ones as lights. Instances
+// in the scene's list of objects become instances of the top level; those nested in other
+// instances are placed inside the bottom level of their parent.
+struct ObjectBuilder<'a> {
+    path: &'a Path,
+    materials: &'a HashMap<&'a str, Arc<dyn Scatter>>,
+    emissive: &'a HashSet<&'a str>,
+    tlas: Tlas,
+    // Bottom level of each mesh file and material, built once for all instances
+    instanced_meshes: HashMap<(PathBuf, String), usize>,
+    // Number of instances the object being added is nested in
+    depth: usize,
+    // Object id of the next shape or instance, counting from one so that zero stays free for
+    // shapes without an id
+    next_id: u32,
+    objects: Vec<Arc<dyn Hit>>,
+    lights: Vec<Arc<dyn Hit>>,
+}
+
+impl ObjectBuilder<'_> {
+    fn invalid(&self, entry: &str, key: &'static str, message: String) -> SceneError {
+        SceneError::Invalid {
+            path: self.path.to_path_buf(),
+            entry: entry.to_string(),
+            key,
+            message,
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn load_mesh(
+        &mut self,
+        file: &Path,
+        material: Arc<dyn Scatter>,
+        entry: &str,
+    ) -> Result<Vec<Arc<dyn Hit>>, SceneError> {
+        let file = self
+            .path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(file);
+        let meshes = obj::load(&file, material).map_err(|source| SceneError::Mesh {
+            path: self.path.to_path_buf(),
+            entry: entry.to_string(),
+            source,
+        })?;
+        let mut triangles = Vec::new();
+        for mesh in meshes {
+            triangles.extend(Arc::new(mesh.with_id(self.next_id())).triangles());
+        }
+        Ok(triangles)
+    }
+
+    fn add(&mut self, object: &ObjectDescription, entry: &str) -> Result<(), SceneError> {
+        let first_shape = self.objects.len();
+        let material_name = object.material();
+        let material = self.materials.get(material_name).cloned().ok_or_else(|| {
+            self.invalid(
+                entry,
+                "material",
+                format!("unknown material `{}`", material_name),
+            )
+        })?;
+
+        match object {
+            ObjectDescription::Sphere { center, radius, .. } => {
+                if *radius <= 0.0 {
+                    return Err(self.invalid(entry, "radius", "must be positive".to_string()));
+                }
+                let sphere = Sphere::new(vec3(*center), *radius, material).with_id(self.next_id());
+                self.objects.push(Arc::new(sphere));
+            }
+            ObjectDescription::Triangle { vertices, .. } => {
+                let [v0, v1, v2] = vertices.map(vec3);
+                let triangle = Triangle::new(v0, v1, v2, material).with_id(self.next_id());
+                self.objects.push(Arc::new(triangle));
+            }
+            ObjectDescription::Quad { corner, u, v, .. } => {
+                let id = self.next_id();
+                self.objects
+                    .extend(quad(vec3(*corner), vec3(*u), vec3(*v), &material, id));
+            }
+            ObjectDescription::Box { min, max, .. } => {
+                let (min, max) = (vec3(*min), vec3(*max));
+                let size = max - min;
+                let dx = Vec3::new(size.x(), 0.0, 0.0);
+                let dy = Vec3::new(0.0, size.y(), 0.0);
+                let dz = Vec3::new(0.0, 0.0, size.z());
+                // Each side is a shape of its own
+                for (corner, u, v) in [
+                    (min, dx, dy),
+                    (min, dy, dz),
+                    (min, dz, dx),
+                    (max, -1.0 * dx, -1.0 * dy),
+                    (max, -1.0 * dy, -1.0 * dz),
+                    (max, -1.0 * dz, -1.0 * dx),
+                ] {
+                    let id = self.next_id();
+                    self.objects.extend(quad(corner, u, v, &material, id));
+                }
+            }
+            ObjectDescription::Mesh { file, .. } => {
+                let triangles = self.load_mesh(file, material, entry)?;
+                self.objects.extend(triangles);
+            }
+            ObjectDescription::Instance {
+                object,
+                translate,
+                rotate,
+                scale,
+            } => {
+                return self.add_instance(object, *translate, *rotate, *scale, entry);
+            }
+        }
+
+        // Mesh faces with their own MTL materials may not emit; sampling them is only wasted work
+        if self.emissive.contains(material_name) {
+            self.lights
+                .extend(self.objects[first_shape..].iter().cloned());
+        }
+        Ok(())
+    }
+
+    fn add_instance(
+        &mut self,
+        object: &ObjectDescription,
+        translate: [f64; 3],
+        rotate: [f64; 3],
+        scale: ScaleParameter,
+        entry: &str,
+    ) -> Result<(), SceneError> {
+        let scale = match scale {
+            ScaleParameter::Uniform(factor) => Vec3::new(factor, factor, factor),
+            ScaleParameter::PerAxis(factors) => vec3(factors),
+        };
+        let matrix = Matrix4::translation(vec3(translate))
+            * Matrix4::rotation(Vec3::new(0.0, 0.0, 1.0), rotate[2])
+            * Matrix4::rotation(Vec3::new(0.0, 1.0, 0.0), rotate[1])
+            * Matrix4::rotation(Vec3::new(1.0, 0.0, 0.0), rotate[0])
+            * Matrix4::scaling(scale);
+        let object_entry = format!("{}.object", entry);
+
+        let key = match object {
+            ObjectDescription::Mesh { file, material } => Some((file.clone(), material.clone())),
+            _ => None,
+        };
+        let cached = key
+            .as_ref()
+            .and_then(|key| self.instanced_meshes.get(key).cloned());
+        let blas = match cached {
+            Some(blas) => blas,
+            None => {
+                // Build the object on its own, then place all of its shapes at once
+                let objects = std::mem::take(&mut self.objects);
+                let lights = std::mem::take(&mut self.lights);
+                self.depth += 1;
+                let result = self.add(object, &object_entry);
+                self.depth -= 1;
+                let shapes = std::mem::replace(&mut self.objects, objects);
+                let inner_lights = std::mem::replace(&mut self.lights, lights);
+                result?;
+                let blas = self
+                    .tlas
+                    .add_blas_with_lights(shapes, inner_lights)
+                    .ok_or_else(|| self.invalid(entry, "object", "has no shapes".to_string()))?;
+                if let Some(key) = key {
+                    self.instanced_meshes.insert(key, blas);
+                }
+                blas
+            }
+        };
+
+        let id = self.next_id();
+        // The top level places the lights of its instances itself
+        let placed = if self.depth == 0 {
+            self.tlas.add_instance_with_id(blas, matrix, id).map(|_| ())
+        } else {
+            let root = self.tlas.blas(blas).cloned();
+            root.and_then(|root| Transformed::new(root, matrix))
+                .map(|instance| {
+                    self.objects.push(Arc::new(instance.with_instance_id(id)));
+                    let lights = self.tlas.placed_lights(blas, matrix);
+                    self.lights.extend(lights);
+                })
+        };
+        if placed.is_none() {
+            return Err(self.invalid(entry, "scale", "must not be zero".to_string()));
+        }
+        Ok(())
+    }
+}
+
+// Two triangles with texture coordinates running from (0, 0) at `corner` to (1, 1) opposite,
+// forming the shape `id`
+fn quad(
+    corner: Point3,
+    u: Vec3,
+    v: Vec3,
+    material: &Arc<dyn Scatter>,
+    id: u32,
+) -> Vec<Arc<dyn Hit>> {
+    let mesh = TriangleMesh::new(
+        vec![corner, corner + u, corner + u + v, corner + v],
+        Vec::new(),
+        vec![[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]],
+        vec![
+            Face {
+                positions: [0, 1, 2],
+                normals: None,
+                uvs: Some([0, 1, 2]),
+            },
+            Face {
+                positions: [0, 2, 3],
+                normals: None,
+                uvs: Some([0, 2, 3]),
+            },
+        ],
+        material.clone(),
+    )
+    .expect("the faces of a quad index its four corners");
+    Arc::new(mesh.with_id(id)).triangles()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    const HEADER: &str = r#"
+[render]
+width = 4
+height = 4
+samples_per_pixel = 1
+max_depth = 2
+
+[camera]
+look_from = [0.0, 0.0, 5.0]
+look_at = [0.0, 0.0, 0.0]
+vertical_fov = 40.0
+"#;
+
+    // Loads `source` as the scene file of a temporary directory named after `name`, which
+    // is removed again afterwards
+    fn load_source(name: &str, source: &str) -> Result<Scene, SceneError> {
+        let directory =
+            std::env::temp_dir().join(format!("rust_raytracer_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("scene.toml");
+        fs::write(&path, source).unwrap();
+        let scene = load(&path);
+        fs::remove_dir_all(&directory).unwrap();
+        scene
+    }
+
+    // Loads `body` after a minimal render and camera section
+    fn load_scene(name: &str, body: &str) -> Result<Scene, SceneError> {
+        load_source(name, &format!("{}\n{}", HEADER, body))
+    }
+
+    fn rejection(result: Result<Scene, SceneError>) -> (String, &'static str, String) {
+        match result {
+            Err(SceneError::Invalid {
+                entry,
+                key,
+                message,
+                ..
+            }) => (entry, key, message),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("the scene was accepted"),
+        }
+    }
+
+    #[test]
+    fn material_cycles_name_the_first_material() {
+        let body = r#"
+[textures]
+flat = { type = "checker", even = [0.5, 0.5, 1.0], odd = [0.5, 0.5, 1.0] }
+
+[materials]
+c = { type = "normal_mapped", material = "a", normal_map = "flat" }
+a = { type = "normal_mapped", material = "b", normal_map = "flat" }
+b = { type = "bump_mapped", material = "c", height = "flat" }
+"#;
+        for _ in 0..4 {
+            let (entry, key, message) = rejection(load_scene("material_cycle", body));
+            assert_eq!(entry, "materials.a");
+            assert_eq!(key, "material");
+            assert_eq!(message, "`b` is part of a cycle of materials");
+        }
+    }
+
+    #[test]
+    fn sellmeier_poles_in_the_visible_range_are_rejected() {
+        let glass = |c: &str| {
+            format!(
+                "[materials]\nglass = {{ type = \"dielectric\", refraction_index = \
+                 {{ type = \"sellmeier\", b = [1.0, 0.3, 1.0], c = {} }} }}",
+                c
+            )
+        };
+        assert!(load_scene("sellmeier", &glass("[0.0132, 0.0623, 155.2]")).is_ok());
+        // A pole at 555nm, between two of the wavelengths the index is checked at
+        let (entry, key, message) =
+            rejection(load_scene("sellmeier", &glass("[0.0132, 0.308, 155.2]")));
+        assert_eq!(
+            (entry.as_str(), key),
+            ("materials.glass", "refraction_index")
+        );
+        assert!(message.starts_with("c must lie outside"), "{}", message);
+    }
+
+    #[test]
+    fn object_ids_are_numbered_per_scene() {
+        let body = r#"
+[materials]
+white = { type = "lambertian", albedo = [0.5, 0.5, 0.5] }
+
+[[objects]]
+type = "sphere"
+center = [-2.0, 0.0, 0.0]
+radius = 1.0
+material = "white"
+
+[[objects]]
+type = "instance"
+translate = [2.0, 0.0, 0.0]
+object = { type = "sphere", center = [0.0, 0.0, 0.0], radius = 1.0, material = "white" }
+"#;
+        let ids = || {
+            let scene = load_scene("object_ids", body).ok().unwrap();
+            [-2.0, 2.0].map(|x| {
+                let ray = Ray::new(Vec3::new(x, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0));
+                scene
+                    .world
+                    .hit(&ray, 0.001, f64::INFINITY)
+                    .unwrap()
+                    .object_id
+            })
+        };
+        // The sphere, then the one inside the instance and the instance itself
+        assert_eq!(ids(), [1, 3]);
+        assert_eq!(ids(), [1, 3]);
+    }
+
+    #[test]
+    fn background_is_a_solid_environment() {
+        let load_with = |top: &str, body: &str| {
+            load_source("background", &format!("{}\n{}\n{}", top, HEADER, body))
+        };
+        let scene = load_with("background = [0.1, 0.2, 0.3]", "").ok().unwrap();
+        let radiance = scene.environment.radiance(Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!([radiance[0], radiance[1], radiance[2]], [0.1, 0.2, 0.3]);
+
+        let environment = "[environment]\ntype = \"solid\"\ncolor = [0.0, 0.0, 0.0]";
+        let (entry, key, _) = rejection(load_with("background = [0.1, 0.2, 0.3]", environment));
+        assert_eq!((entry.as_str(), key), ("scene", "background"));
+    }
+
+    #[test]
+    fn shipped_scenes_load() {
+        let scenes = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes");
+        let mut count = 0;
+        for file in fs::read_dir(scenes).unwrap() {
+            let path = file.unwrap().path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "toml")
+            {
+                if let Err(error) = load(&path) {
+                    panic!("{}", error);
+                }
+                count += 1;
+            }
+        }
+        assert!(count >= 9);
+    }
+
+    #[test]
+    fn parse_errors_name_the_entry_and_key() {
+        let body = "[materials]\nred = { type = \"lambertian\", albedo = [1.0, 0.0] }";
+        match load_scene("parse_error", body) {
+            Err(error @ SceneError::Parse { .. }) => {
+                let message = error.to_string();
+                // The position and text of the offending entry
+                assert!(message.contains("line 14"), "{}", message);
+                assert!(message.contains("red = {"), "{}", message);
+            }
+            _ => panic!("expected a parse error"),
+        }
+        match load_scene(
+            "unknown_key",
+            "[environment]\ntype = \"solid\"\ncolour = [0, 0, 0]",
+        ) {
+            Err(error @ SceneError::Parse { .. }) => {
+                let message = error.to_string();
+                assert!(message.contains("colour"), "{}", message);
+            }
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn degenerate_cameras_are_rejected() {
+        let camera = |look_from: &str, up: &str, vertical_fov: &str| {
+            let source = format!(
+                "[render]\nwidth = 4\nheight = 4\nsamples_per_pixel = 1\nmax_depth = 2\n\n\
+                 [camera]\nlook_from = {}\nlook_at = [0.0, 0.0, 0.0]\nup = {}\nvertical_fov = {}\n",
+                look_from, up, vertical_fov
+            );
+            load_source("camera", &source)
+        };
+        let (front, up) = ("[0.0, 0.0, 5.0]", "[0.0, 1.0, 0.0]");
+        assert!(camera(front, up, "40.0").is_ok());
+        for (look_from, up, vertical_fov, key) in [
+            (front, up, "0.0", "vertical_fov"),
+            (front, up, "-10.0", "vertical_fov"),
+            (front, up, "180.0", "vertical_fov"),
+            (front, up, "nan", "vertical_fov"),
+            ("[0.0, 0.0, 0.0]", up, "40.0", "look_at"),
+            (front, "[0.0, 0.0, -2.0]", "40.0", "up"),
+            (front, "[0.0, 0.0, 0.0]", "40.0", "up"),
+        ] {
+            let (entry, rejected, _) = rejection(camera(look_from, up, vertical_fov));
+            assert_eq!((entry.as_str(), rejected), ("camera", key));
+        }
+    }
+
+    #[test]
+    fn metal_fuzz_must_be_a_fraction() {
+        let metal = |fuzz: &str| {
+            format!(
+                "[materials]\nsteel = {{ type = \"metal\", albedo = [0.8, 0.8, 0.8], fuzz = {} }}",
+                fuzz
+            )
+        };
+        assert!(load_scene("fuzz", &metal("0.3")).is_ok());
+        for fuzz in ["-0.1", "1.5", "nan"] {
+            let (entry, key, _) = rejection(load_scene("fuzz", &metal(fuzz)));
+            assert_eq!((entry.as_str(), key), ("materials.steel", "fuzz"));
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// spectrum.rs
+// Spectral rendering: wavelengths carried by a path, the CIE standard observer, upsampling of
+// RGB colors to spectra and wavelength dependent refractive indices. Wavelengths are in nm.
+use super::vec::{Color, Vec3};
+
+use std::sync::OnceLock;
+
+pub const MIN_WAVELENGTH: f64 = 360.0;
+pub const MAX_WAVELENGTH: f64 = 830.0;
+
+// Wavelengths of one path, one per component of a `Vec3`: a hero wavelength picked uniformly
+// and two more evenly spaced across the visible range after it
+// (Wilkie et al., "Hero Wavelength Spectral Sampling", 2014)
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    lambda: [f64; 3],
+    // Set once the path took a direction that only holds for the hero wavelength
+    hero_only: bool,
+}
+
+impl Wavelengths {
+    pub fn sample(u: f64) -> Wavelengths {
+        let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+        let hero = MIN_WAVELENGTH + u * range;
+        let rotated = |i: usize| {
+            let lambda = hero + i as f64 * range / 3.0;
+            if lambda >= MAX_WAVELENGTH {
+                lambda - range
+            } else {
+                lambda
+            }
+        };
+        Wavelengths {
+            lambda: [hero, rotated(1), rotated(2)],
+            hero_only: false,
+        }
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+
+    // Drops the secondary wavelengths, returning the weight that makes the hero alone stand in
+    // for all of them. Only the first call drops anything.
+    pub fn terminate_secondary(&mut self) -> Vec3 {
+        if self.hero_only {
+            return Vec3::new(1.0, 1.0, 1.0);
+        }
+        self.hero_only = true;
+        Vec3::new(3.0, 0.0, 0.0)
+    }
+
+    // Values at the wavelengths of the smooth spectrum that has the linear sRGB color `color`.
+    // Spectra of colors inside the gamut stay within [0, 1] where the color does, up to the
+    // clipping of negative values for very saturated colors.
+    pub fn upsample(&self, color: Color) -> Vec3 {
+        let coefficients = multiply(&observer().upsampling, color);
+        let value = |lambda: f64| coefficients.dot(basis(lambda)).max(0.0);
+        Vec3::new(
+            value(self.lambda[0]),
+            value(self.lambda[1]),
+            value(self.lambda[2]),
+        )
+    }
+
+    // Monte Carlo estimate of the CIE XYZ color of a spectrum from its values at the
+    // wavelengths, scaled so the constant spectrum of one has a luminance Y of one
+    pub fn to_xyz(&self, values: Vec3) -> Vec3 {
+        let density = 1.0 / (MAX_WAVELENGTH - MIN_WAVELENGTH);
+        let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+        for (i, &lambda) in self.lambda.iter().enumerate() {
+            xyz += values[i] * cie_xyz(lambda);
+        }
+        xyz / (3.0 * density * observer().luminance)
+    }
+}
+
+// Linear sRGB from XYZ, white balanced so the constant spectrum is white. RGB colors and
+// upsampled spectra use the same reference, so white light on a white surface stays white.
+pub fn xyz_to_rgb(xyz: Vec3) -> Color {
+    let rgb = multiply(&XYZ_TO_SRGB, xyz);
+    let white = observer().white;
+    Color::new(
+        rgb.x() / white.x(),
+        rgb.y() / white.y(),
+        rgb.z() / white.z(),
+    )
+}
+
+// Index of refraction over wavelength
+#[derive(Clone, Copy)]
+pub enum RefractiveIndex {
+    Constant(f64),
+    // n = a + b / λ², with λ in micrometers
+    Cauchy { a: f64, b: f64 },
+    // n² = 1 + Σ b_i λ² / (λ² - c_i), with λ in micrometers and c_i in µm²
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl RefractiveIndex {
+    // Where dispersive indices are evaluated outside of spectral rendering, the helium d-line
+    // at which glass catalogs quote their index
+    pub const REFERENCE_WAVELENGTH: f64 = 587.56;
+
+    pub fn at(&self, wavelength: f64) -> f64 {
+        let micrometers = wavelength * 1.0e-3;
+        let squared = micrometers * micrometers;
+        match *self {
+            RefractiveIndex::Constant(index) => index,
+            RefractiveIndex::Cauchy { a, b } => a + b / squared,
+            RefractiveIndex::Sellmeier { b, c } => {
+                let sum: f64 = (0..3).map(|i| b[i] * squared / (squared - c[i])).sum();
+                (1.0 + sum).max(0.0).sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, RefractiveIndex::Constant(_))
+    }
+}
+
+const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.9692660, 1.8760108, 0.0415560],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+// CIE 1931 2° color matching functions, from the multi-lobe fit of Wyman et al.,
+// "Simple Analytic Approximations to the CIE XYZ Color Matching Functions", 2013
+fn cie_xyz(lambda: f64) -> Vec3 {
+    let lobe = |mean: f64, below: f64, above: f64| {
+        let t = (lambda - mean) / if lambda < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7)
+            - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    )
+}
+
+// Smooth red, green and blue spectra that add up to one at every wavelength, so any mix of
+// them with weights in [0, 1] stays a valid reflectance
+fn basis(lambda: f64) -> Vec3 {
+    const BLUE_EDGE: f64 = 495.0;
+    const RED_EDGE: f64 = 585.0;
+    const WIDTH: f64 = 10.0;
+    let sigmoid = |x: f64| 1.0 / (1.0 + (-x).exp());
+    let red = sigmoid((lambda - RED_EDGE) / WIDTH);
+    let blue = 1.0 - sigmoid((lambda - BLUE_EDGE) / WIDTH);
+    Vec3::new(red, 1.0 - red - blue, blue)
+}
+
+// Integrals over the observer that only need to be computed once
+struct Observer {
+    // ∫ ȳ(λ) dλ, the luminance of the constant spectrum of one
+    luminance: f64,
+    // Linear sRGB of the constant spectrum of luminance one, before white balancing
+    white: Color,
+    // Maps a linear sRGB color to the weights of the basis spectra with that color
+    upsampling: [[f64; 3]; 3],
+}
+
+fn observer() -> &'static Observer {
+    static OBSERVER: OnceLock<Observer> = OnceLock::new();
+    OBSERVER.get_or_init(|| {
+        // Midpoint rule at 1nm
+        let integrate = |spectrum: &dyn Fn(f64) -> Vec3| {
+            let mut sum = Vec3::new(0.0, 0.0, 0.0);
+            let mut lambda = MIN_WAVELENGTH + 0.5;
+            while lambda < MAX_WAVELENGTH {
+                sum += spectrum(lambda);
+                lambda += 1.0;
+            }
+            sum
+        };
+
+        let white_xyz = integrate(&cie_xyz);
+        let luminance = white_xyz.y();
+        let white = multiply(&XYZ_TO_SRGB, white_xyz / luminance);
+        // White balanced colors of the basis spectra, which become the matrix columns
+        let colors = [0, 1, 2].map(|channel| {
+            let xyz = integrate(&|lambda| basis(lambda)[channel] * cie_xyz(lambda)) / luminance;
+            let rgb = multiply(&XYZ_TO_SRGB, xyz);
+            Color::new(
+                rgb.x() / white.x(),
+                rgb.y() / white.y(),
+                rgb.z() / white.z(),
+            )
+        });
+        let matrix = [0, 1, 2].map(|row| colors.map(|color| color[row]));
+
+        Observer {
+            luminance,
+            white,
+            upsampling: invert(&matrix),
+        }
+    })
+}
+
+fn multiply(matrix: &[[f64; 3]; 3], v: Vec3) -> Vec3 {
+    Vec3::new(
+        v.dot(Vec3::new(matrix[0][0], matrix[0][1], matrix[0][2])),
+        v.dot(Vec3::new(matrix[1][0], matrix[1][1], matrix[1][2])),
+        v.dot(Vec3::new(matrix[2][0], matrix[2][1], matrix[2][2])),
+    )
+}
+
+fn invert(m: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cofactor = |row: usize, column: usize| {
+        let (r0, r1) = ((row + 1) % 3, (row + 2) % 3);
+        let (c0, c1) = ((column + 1) % 3, (column + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3)
+        .map(|column| m[0][column] * cofactor(0, column))
+        .sum();
+    let mut inverse = [[0.0; 3]; 3];
+    for (row, values) in inverse.iter_mut().enumerate() {
+        for (column, value) in values.iter_mut().enumerate() {
+            *value = cofactor(column, row) / determinant;
+        }
+    }
+    inverse
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hero wavelengths spread evenly over the visible range
+    fn stratified(count: usize) -> impl Iterator<Item = Wavelengths> {
+        (0..count).map(move |i| Wavelengths::sample((i as f64 + 0.5) / count as f64))
+    }
+
+    #[test]
+    fn white_upsamples_to_one() {
+        for wavelengths in stratified(100) {
+            let values = wavelengths.upsample(Color::new(1.0, 1.0, 1.0));
+            for i in 0..3 {
+                assert!((values[i] - 1.0).abs() < 1.0e-9, "{}", values[i]);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_spectrum_is_white() {
+        let count = 1000;
+        let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+        for wavelengths in stratified(count) {
+            xyz += wavelengths.to_xyz(Vec3::new(1.0, 1.0, 1.0));
+        }
+        let rgb = xyz_to_rgb(xyz / count as f64);
+        for i in 0..3 {
+            assert!((rgb[i] - 1.0).abs() < 1.0e-3, "{}", rgb[i]);
+        }
+    }
+}
@@ -2,14 +2,18 @@
 use super::bvh::Aabb;
 use super::hit::{Hit, HitRecord};
 use super::material::Scatter;
+use super::random::random;
 use super::ray::Ray;
+use super::sampling::tangent_frame;
 use super::vec::{Point3, Vec3};
+use std::f64::consts::PI;
 use std::sync::Arc;
 
 pub struct Sphere {
     center: Point3,
     radius: f64,
     material: Arc<dyn Scatter>,
+    id: u32,
 }
 
 impl Sphere {
@@ -18,8 +22,48 @@ impl Sphere {
             center,
             radius,
             material,
+            id: 0,
         }
     }
+
+    // Reported as the object id of hits
+    pub fn with_id(mut self, id: u32) -> Sphere {
+        self.id = id;
+        self
+    }
+
+    // Longitude and latitude of a point on the unit sphere, both in [0, 1].
+    // u goes around the y axis starting at -x, v from the bottom pole to the top pole.
+    fn uv(point: Point3) -> (f64, f64) {
+        let theta = (-point.y()).clamp(-1.0, 1.0).acos();
+        let phi = (-point.z()).atan2(point.x()) + PI;
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    // Derivatives of the point at `normal` with respect to the coordinates of `uv`
+    fn tangents(&self, normal: Vec3) -> (Vec3, Vec3) {
+        let (x, y, z) = (normal.x(), normal.y(), normal.z());
+        let dpdu = 2.0 * PI * self.radius * Vec3::new(z, 0.0, -x);
+        // Distance from the y axis, zero at the poles where u is undefined
+        let radial = (x * x + z * z).sqrt();
+        if radial < 1.0e-12 {
+            return (
+                Vec3::new(0.0, 0.0, -2.0 * PI * self.radius),
+                Vec3::new(PI * self.radius, 0.0, 0.0),
+            );
+        }
+        let dpdv = PI * self.radius * Vec3::new(-x * y / radial, radial, -y * z / radial);
+        (dpdu, dpdv)
+    }
+
+    // Cosine of the half-angle of the cone the sphere subtends from outside at `origin`
+    fn cos_theta_max(&self, origin: Point3) -> Option<f64> {
+        let distance_squared = (self.center - origin).length().powi(2);
+        if distance_squared <= self.radius.powi(2) {
+            return None;
+        }
+        Some((1.0 - self.radius.powi(2) / distance_squared).sqrt())
+    }
 }
 
 impl Hit for Sphere {
@@ -43,15 +87,24 @@ impl Hit for Sphere {
             }
         }
 
+        let point = ray.at(root);
+        let outward_normal = (point - self.center) / self.radius;
+        let (u, v) = Sphere::uv(outward_normal);
+        let (dpdu, dpdv) = self.tangents(outward_normal);
         let mut record = HitRecord {
             time: root,
-            point: ray.at(root),
+            point,
             material: self.material.clone(),
-            normal: (ray.at(root) - self.center) / self.radius,
+            normal: outward_normal,
+            geometric_normal: outward_normal,
             front_face: false,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            object_id: self.id,
+            primitive_id: 0,
         };
-
-        let outward_normal = (record.point - self.center) / self.radius;
         record.set_face_normal(ray, outward_normal);
         Some(record)
     }
@@ -61,4 +114,32 @@ impl Hit for Sphere {
             self.center + Vec3::new(self.radius, self.radius, self.radius),
         ))
     }
+
+    // Uniform over the cone of directions that see the sphere, which is cheaper and less
+    // noisy than sampling its surface area
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        let Some(cos_theta_max) = self.cos_theta_max(origin) else {
+            return 0.0;
+        };
+        if self
+            .hit(&Ray::new(origin, direction), 0.001, f64::INFINITY)
+            .is_none()
+        {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let Some(cos_theta_max) = self.cos_theta_max(origin) else {
+            return self.center - origin;
+        };
+        let phi = 2.0 * PI * random();
+        let z = 1.0 + random() * (cos_theta_max - 1.0);
+        let sin_theta = (1.0 - z * z).sqrt();
+
+        let w = (self.center - origin).normalized();
+        let (u, v) = tangent_frame(w);
+        phi.cos() * sin_theta * u + phi.sin() * sin_theta * v + z * w
+    }
 }
@@ -0,0 +1,370 @@
+// texture.rs
+use super::image::Image;
+use super::vec::{Color, Point3, Vec3};
+
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+
+// Color that varies over a surface, looked up by surface coordinates and hit point
+pub trait Texture: Send + Sync {
+    fn value(&self, u: f64, v: f64, point: Point3) -> Color;
+}
+
+pub struct SolidColor {
+    color: Color,
+}
+
+impl SolidColor {
+    pub fn new(color: Color) -> SolidColor {
+        SolidColor { color }
+    }
+}
+
+impl Texture for SolidColor {
+    fn value(&self, _u: f64, _v: f64, _point: Point3) -> Color {
+        self.color
+    }
+}
+
+// Another texture multiplied by a constant factor
+pub struct Scaled {
+    texture: Arc<dyn Texture>,
+    scale: f64,
+}
+
+impl Scaled {
+    pub fn new(texture: Arc<dyn Texture>, scale: f64) -> Scaled {
+        Scaled { texture, scale }
+    }
+}
+
+impl Texture for Scaled {
+    fn value(&self, u: f64, v: f64, point: Point3) -> Color {
+        self.scale * self.texture.value(u, v, point)
+    }
+}
+
+// Alternates between two textures in a 3D grid of cubes with edge length `scale`
+pub struct Checker {
+    even: Arc<dyn Texture>,
+    odd: Arc<dyn Texture>,
+    scale: f64,
+}
+
+impl Checker {
+    pub fn new(even: Arc<dyn Texture>, odd: Arc<dyn Texture>, scale: f64) -> Checker {
+        Checker { even, odd, scale }
+    }
+}
+
+impl Texture for Checker {
+    fn value(&self, u: f64, v: f64, point: Point3) -> Color {
+        let cell = (point.x() / self.scale).floor()
+            + (point.y() / self.scale).floor()
+            + (point.z() / self.scale).floor();
+        if cell.rem_euclid(2.0) == 0.0 {
+            self.even.value(u, v, point)
+        } else {
+            self.odd.value(u, v, point)
+        }
+    }
+}
+
+// How texture coordinates outside [0, 1] are mapped back onto the image
+#[derive(Clone, Copy)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    fn wrap(self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        let index = match self {
+            WrapMode::Repeat => index.rem_euclid(size),
+            WrapMode::Clamp => index.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let index = index.rem_euclid(2 * size);
+                if index < size {
+                    index
+                } else {
+                    2 * size - 1 - index
+                }
+            }
+        };
+        index as usize
+    }
+}
+
+// Bilinearly filtered image, with v = 0 at the bottom row as in OBJ texture coordinates
+pub struct ImageTexture {
+    image: Image,
+    wrap: WrapMode,
+}
+
+impl ImageTexture {
+    pub fn new(image: Image, wrap: WrapMode) -> ImageTexture {
+        ImageTexture { image, wrap }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _point: Point3) -> Color {
+        let (width, height) = (self.image.width(), self.image.height());
+        // Texel centers sit at half-integer coordinates
+        let x = u * width as f64 - 0.5;
+        let y = (1.0 - v) * height as f64 - 0.5;
+        if !x.is_finite() || !y.is_finite() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let texel = |x: i64, y: i64| {
+            self.image
+                .pixel(self.wrap.wrap(x, width), self.wrap.wrap(y, height))
+        };
+        let top = (1.0 - tx) * texel(x0, y0) + tx * texel(x0 + 1, y0);
+        let bottom = (1.0 - tx) * texel(x0, y0 + 1) + tx * texel(x0 + 1, y0 + 1);
+        (1.0 - ty) * top + ty * bottom
+    }
+}
+
+// Gradient noise after Ken Perlin, built from its own seed so scenes render reproducibly
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    permutations: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    const POINT_COUNT: usize = 256;
+
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let gradients = (0..Self::POINT_COUNT)
+            .map(|_| loop {
+                let gradient = Vec3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                );
+                let length = gradient.length();
+                if 1.0e-3 < length && length <= 1.0 {
+                    break gradient / length;
+                }
+            })
+            .collect();
+        let mut permutation = || {
+            let mut permutation: Vec<usize> = (0..Self::POINT_COUNT).collect();
+            permutation.shuffle(&mut rng);
+            permutation
+        };
+        let permutations = [permutation(), permutation(), permutation()];
+        Perlin {
+            gradients,
+            permutations,
+        }
+    }
+
+    // Smooth noise in [-1, 1]
+    pub fn noise(&self, point: Point3) -> f64 {
+        let cell = [point.x().floor(), point.y().floor(), point.z().floor()];
+        let fraction = [
+            point.x() - cell[0],
+            point.y() - cell[1],
+            point.z() - cell[2],
+        ];
+        // Hermite smoothing hides the grid in the interpolation
+        let weight = fraction.map(|t| t * t * (3.0 - 2.0 * t));
+
+        let mask = Self::POINT_COUNT as i64 - 1;
+        let cell = cell.map(|c| c as i64);
+        let mut accumulated = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.permutations[0][((cell[0] + di) & mask) as usize]
+                        ^ self.permutations[1][((cell[1] + dj) & mask) as usize]
+                        ^ self.permutations[2][((cell[2] + dk) & mask) as usize];
+                    let offset = Vec3::new(
+                        fraction[0] - di as f64,
+                        fraction[1] - dj as f64,
+                        fraction[2] - dk as f64,
+                    );
+                    let blend = |d: i64, w: f64| if d == 1 { w } else { 1.0 - w };
+                    accumulated += blend(di, weight[0])
+                        * blend(dj, weight[1])
+                        * blend(dk, weight[2])
+                        * self.gradients[index].dot(offset);
+                }
+            }
+        }
+        accumulated
+    }
+
+    // Sum of `octaves` layers of noise, each at twice the frequency and half the amplitude
+    pub fn turbulence(&self, point: Point3, octaves: u32) -> f64 {
+        let mut accumulated = 0.0;
+        let mut point = point;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            accumulated += weight * self.noise(point);
+            weight *= 0.5;
+            point *= 2.0;
+        }
+        accumulated.abs()
+    }
+}
+
+// Perlin noise remapped to [0, 1] and tinted with `color`
+pub struct NoiseTexture {
+    perlin: Perlin,
+    color: Color,
+    scale: f64,
+}
+
+impl NoiseTexture {
+    pub fn new(color: Color, scale: f64, seed: u64) -> NoiseTexture {
+        NoiseTexture {
+            perlin: Perlin::new(seed),
+            color,
+            scale,
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f64, _v: f64, point: Point3) -> Color {
+        0.5 * (1.0 + self.perlin.noise(self.scale * point)) * self.color
+    }
+}
+
+pub struct TurbulenceTexture {
+    perlin: Perlin,
+    color: Color,
+    scale: f64,
+    octaves: u32,
+}
+
+impl TurbulenceTexture {
+    pub fn new(color: Color, scale: f64, octaves: u32, seed: u64) -> TurbulenceTexture {
+        TurbulenceTexture {
+            perlin: Perlin::new(seed),
+            color,
+            scale,
+            octaves,
+        }
+    }
+}
+
+impl Texture for TurbulenceTexture {
+    fn value(&self, _u: f64, _v: f64, point: Point3) -> Color {
+        self.perlin.turbulence(self.scale * point, self.octaves) * self.color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(value: f64) -> Color {
+        Color::new(value, value, value)
+    }
+
+    fn solid(value: f64) -> Arc<dyn Texture> {
+        Arc::new(SolidColor::new(gray(value)))
+    }
+
+    #[test]
+    fn checker_cells_alternate_in_every_direction() {
+        let checker = Checker::new(solid(1.0), solid(0.0), 0.5);
+        let at = |x: f64, y: f64, z: f64| checker.value(0.0, 0.0, Point3::new(x, y, z)).x();
+        assert_eq!(at(0.1, 0.1, 0.1), 1.0);
+        assert_eq!(at(0.6, 0.1, 0.1), 0.0);
+        assert_eq!(at(0.1, 0.6, 0.1), 0.0);
+        assert_eq!(at(0.1, 0.1, 0.6), 0.0);
+        assert_eq!(at(0.6, 0.6, 0.1), 1.0);
+        assert_eq!(at(0.6, 0.6, 0.6), 0.0);
+        // Cells continue across zero instead of mirroring there
+        assert_eq!(at(-0.1, 0.1, 0.1), 0.0);
+        assert_eq!(at(-0.6, 0.1, 0.1), 1.0);
+        assert_eq!(at(-0.1, -0.1, 0.1), 1.0);
+    }
+
+    #[test]
+    fn wrap_modes_map_indices_back_onto_the_image() {
+        let wrapped = |mode: WrapMode| [-5, -1, 0, 3, 4, 6, 9].map(|index| mode.wrap(index, 4));
+        assert_eq!(wrapped(WrapMode::Repeat), [3, 3, 0, 3, 0, 2, 1]);
+        assert_eq!(wrapped(WrapMode::Clamp), [0, 0, 0, 3, 3, 3, 3]);
+        assert_eq!(wrapped(WrapMode::Mirror), [3, 0, 0, 3, 3, 1, 1]);
+    }
+
+    // 2x2 image with texel values 0 and 1 in the top row and 2 and 3 in the bottom row
+    fn texture(wrap: WrapMode) -> ImageTexture {
+        let pixels = vec![gray(0.0), gray(1.0), gray(2.0), gray(3.0)];
+        ImageTexture::new(Image::from_pixels(2, 2, pixels), wrap)
+    }
+
+    fn lookup(texture: &ImageTexture, u: f64, v: f64) -> f64 {
+        texture.value(u, v, Point3::new(0.0, 0.0, 0.0)).x()
+    }
+
+    #[test]
+    fn images_are_filtered_between_texel_centers() {
+        let texture = texture(WrapMode::Clamp);
+        // Texel centers, with v running up from the bottom row
+        assert_eq!(lookup(&texture, 0.25, 0.75), 0.0);
+        assert_eq!(lookup(&texture, 0.75, 0.75), 1.0);
+        assert_eq!(lookup(&texture, 0.25, 0.25), 2.0);
+        assert_eq!(lookup(&texture, 0.75, 0.25), 3.0);
+        // Halfway between centers, and the middle of all four
+        assert_eq!(lookup(&texture, 0.5, 0.75), 0.5);
+        assert_eq!(lookup(&texture, 0.25, 0.5), 1.0);
+        assert_eq!(lookup(&texture, 0.5, 0.5), 1.5);
+        // Clamped edges hold the outer texels
+        assert_eq!(lookup(&texture, 0.0, 0.75), 0.0);
+        assert_eq!(lookup(&texture, 1.0, 0.25), 3.0);
+        assert_eq!(lookup(&texture, -3.0, 7.0), 0.0);
+        assert_eq!(lookup(&texture, f64::NAN, 0.5), 0.0);
+    }
+
+    #[test]
+    fn image_edges_follow_the_wrap_mode() {
+        // At u = 0, halfway between the left texel and the one beyond the edge
+        let repeat = texture(WrapMode::Repeat);
+        assert_eq!(lookup(&repeat, 0.0, 0.75), 0.5);
+        assert_eq!(lookup(&repeat, 1.25, 0.75), lookup(&repeat, 0.25, 0.75));
+        assert_eq!(lookup(&repeat, -0.75, 0.25), lookup(&repeat, 0.25, 0.25));
+
+        let mirror = texture(WrapMode::Mirror);
+        assert_eq!(lookup(&mirror, 0.0, 0.75), 0.0);
+        assert_eq!(lookup(&mirror, 1.25, 0.75), 1.0);
+        assert_eq!(lookup(&mirror, -0.25, 0.75), 0.0);
+        assert_eq!(lookup(&mirror, -0.75, 0.25), 3.0);
+    }
+
+    #[test]
+    fn perlin_noise_is_determined_by_its_seed() {
+        let points: Vec<Point3> = (0..50)
+            .map(|i| {
+                let i = i as f64;
+                Point3::new(0.37 * i, 1.3 - 0.11 * i, 0.53 * i * i % 17.0)
+            })
+            .collect();
+        let noise = |seed: u64| -> Vec<f64> {
+            let perlin = Perlin::new(seed);
+            points.iter().map(|&point| perlin.noise(point)).collect()
+        };
+        assert_eq!(noise(7), noise(7));
+        assert_ne!(noise(7), noise(8));
+        for value in noise(7) {
+            assert!((-1.0..=1.0).contains(&value));
+        }
+        // Zero at the corners of the lattice, where every offset vanishes
+        assert_eq!(Perlin::new(7).noise(Point3::new(3.0, -2.0, 5.0)), 0.0);
+    }
+}
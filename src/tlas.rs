@@ -0,0 +1,250 @@
+// tlas.rs
+// Two-level acceleration structure. Bottom-level hierarchies over the shapes of each mesh are
+// built once and shared by all instances of it; the top level only spans the instances, so it
+// can be rebuilt every frame when nothing but their transformations changes.
+use super::bvh::{Aabb, LinearBvh};
+use super::hit::{Hit, HitRecord};
+use super::ray::Ray;
+use super::transform::{Matrix4, Transformed};
+
+use std::sync::Arc;
+
+#[derive(Clone)]
+struct Blas {
+    root: Arc<dyn Hit>,
+    // Those of its shapes that emit light, for sampling them directly
+    lights: Vec<Arc<dyn Hit>>,
+}
+
+#[derive(Clone)]
+struct Instance {
+    blas: usize,
+    transform: Matrix4,
+    id: Option<u32>,
+    // The bottom level as it is for the identity, otherwise wrapped in its transformation
+    placed: Arc<dyn Hit>,
+    // The lights of the bottom level placed the same way
+    lights: Vec<Arc<dyn Hit>>,
+}
+
+#[derive(Clone, Default)]
+pub struct Tlas {
+    blases: Vec<Blas>,
+    instances: Vec<Instance>,
+    root: Option<Arc<dyn Hit>>,
+    lights: Vec<Arc<dyn Hit>>,
+}
+
+impl Tlas {
+    pub fn new() -> Tlas {
+        Tlas::default()
+    }
+
+    // Builds the bottom-level hierarchy over `shapes`, returning its index for placing
+    // instances of it. None when there are no shapes.
+    pub fn add_blas(&mut self, shapes: Vec<Arc<dyn Hit>>) -> Option<usize> {
+        self.add_blas_with_lights(shapes, Vec::new())
+    }
+
+    // Like `add_blas`, where `lights` are those of the shapes that emit light. Every instance
+    // places them along with the shapes, see `lights`.
+    pub fn add_blas_with_lights(
+        &mut self,
+        mut shapes: Vec<Arc<dyn Hit>>,
+        lights: Vec<Arc<dyn Hit>>,
+    ) -> Option<usize> {
+        let root: Arc<dyn Hit> = match shapes.len() {
+            0 => return None,
+            1 => shapes.pop().unwrap(),
+            _ => Arc::new(LinearBvh::new(shapes)),
+        };
+        self.blases.push(Blas { root, lights });
+        Some(self.blases.len() - 1)
+    }
+
+    pub fn blas(&self, blas: usize) -> Option<&Arc<dyn Hit>> {
+        self.blases.get(blas).map(|blas| &blas.root)
+    }
+
+    // The lights of `blas` as an instance with `transform` places them. Only transformations
+    // that preserve angles keep lights samplable, so there are none for any other.
+    pub fn placed_lights(&self, blas: usize, transform: Matrix4) -> Vec<Arc<dyn Hit>> {
+        let Some(blas) = self.blases.get(blas) else {
+            return Vec::new();
+        };
+        if transform == Matrix4::identity() {
+            return blas.lights.clone();
+        }
+        if !transform.is_conformal() {
+            return Vec::new();
+        }
+        blas.lights
+            .iter()
+            .filter_map(|light| Transformed::new(light.clone(), transform))
+            .map(|light| Arc::new(light) as Arc<dyn Hit>)
+            .collect()
+    }
+
+    // Places the bottom level `blas`, returning the index of the instance. None when there is
+    // no such bottom level or the transformation cannot be inverted. Takes effect with the
+    // next `rebuild`.
+    pub fn add_instance(&mut self, blas: usize, transform: Matrix4) -> Option<usize> {
+        self.push_instance(blas, transform, None)
+    }
+
+    // Like `add_instance`, but hits on the instance report `id` as their object id
+    pub fn add_instance_with_id(
+        &mut self,
+        blas: usize,
+        transform: Matrix4,
+        id: u32,
+    ) -> Option<usize> {
+        self.push_instance(blas, transform, Some(id))
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+
+    // None when there is no such instance
+    pub fn transform(&self, instance: usize) -> Option<Matrix4> {
+        self.instances
+            .get(instance)
+            .map(|instance| instance.transform)
+    }
+
+    // Moves an instance along with its lights, leaving it where it was and returning false
+    // when there is no such instance or the transformation cannot be inverted. Takes effect
+    // with the next `rebuild`.
+    pub fn set_transform(&mut self, instance: usize, transform: Matrix4) -> bool {
+        let Some(&Instance { blas, id, .. }) = self.instances.get(instance) else {
+            return false;
+        };
+        let Some(placed) = self.place(blas, transform, id) else {
+            return false;
+        };
+        self.instances[instance] = placed;
+        true
+    }
+
+    // Builds the top level over the current instances, leaving the bottom levels untouched
+    pub fn rebuild(&mut self) {
+        self.root = match self.instances.len() {
+            0 => None,
+            1 => Some(self.instances[0].placed.clone()),
+            _ => {
+                let placed = self.instances.iter().map(|i| i.placed.clone()).collect();
+                Some(Arc::new(LinearBvh::new(placed)))
+            }
+        };
+        self.lights = self
+            .instances
+            .iter()
+            .flat_map(|instance| instance.lights.iter().cloned())
+            .collect();
+    }
+
+    // The lights of all instances, where they were at the last `rebuild`
+    pub fn lights(&self) -> &[Arc<dyn Hit>] {
+        &self.lights
+    }
+
+    fn push_instance(&mut self, blas: usize, transform: Matrix4, id: Option<u32>) -> Option<usize> {
+        let instance = self.place(blas, transform, id)?;
+        self.instances.push(instance);
+        Some(self.instances.len() - 1)
+    }
+
+    // None when there is no bottom level `blas` or `transform` cannot be inverted
+    fn place(&self, blas: usize, transform: Matrix4, id: Option<u32>) -> Option<Instance> {
+        let root = self.blas(blas)?.clone();
+        // Instances with an id are always wrapped, since the wrapper reports it
+        let placed: Arc<dyn Hit> = if id.is_none() && transform == Matrix4::identity() {
+            root
+        } else {
+            let transformed = Transformed::new(root, transform)?;
+            Arc::new(match id {
+                Some(id) => transformed.with_instance_id(id),
+                None => transformed,
+            })
+        };
+        Some(Instance {
+            blas,
+            transform,
+            id,
+            placed,
+            lights: self.placed_lights(blas, transform),
+        })
+    }
+}
+
+impl Hit for Tlas {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        self.root.as_ref()?.hit(ray, t_min, t_max)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.root.as_ref()?.bounding_box()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::DiffuseLight;
+    use crate::sphere::Sphere;
+    use crate::vec::{Color, Vec3};
+
+    fn light() -> Arc<dyn Hit> {
+        let material = Arc::new(DiffuseLight::new(Color::new(1.0, 1.0, 1.0)));
+        Arc::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, material))
+    }
+
+    // Density of sampling the light straight down from high above (x, 0, 0)
+    fn pdf_above(light: &Arc<dyn Hit>, x: f64) -> f64 {
+        light.pdf_value(Vec3::new(x, 20.0, 0.0), Vec3::new(0.0, -1.0, 0.0))
+    }
+
+    #[test]
+    fn missing_bottom_levels_are_rejected() {
+        let mut tlas = Tlas::new();
+        assert_eq!(tlas.add_instance(0, Matrix4::identity()), None);
+        let blas = tlas.add_blas(vec![light()]).unwrap();
+        assert_eq!(tlas.add_instance(blas + 1, Matrix4::identity()), None);
+        assert!(tlas.blas(blas + 1).is_none());
+        assert!(!tlas.set_transform(0, Matrix4::identity()));
+        assert!(tlas.transform(0).is_none());
+    }
+
+    #[test]
+    fn lights_follow_their_instances() {
+        let mut tlas = Tlas::new();
+        let light = light();
+        let blas = tlas
+            .add_blas_with_lights(vec![light.clone()], vec![light])
+            .unwrap();
+        let instance = tlas
+            .add_instance(blas, Matrix4::translation(Vec3::new(10.0, 0.0, 0.0)))
+            .unwrap();
+        // Stretched lights cannot be sampled
+        tlas.add_instance(blas, Matrix4::scaling(Vec3::new(2.0, 1.0, 1.0)))
+            .unwrap();
+        assert!(tlas.lights().is_empty());
+        tlas.rebuild();
+        assert_eq!(tlas.lights().len(), 1);
+        assert!(pdf_above(&tlas.lights()[0], 10.0) > 0.0);
+        assert_eq!(pdf_above(&tlas.lights()[0], -10.0), 0.0);
+
+        let moved = Matrix4::translation(Vec3::new(-10.0, 0.0, 0.0));
+        assert!(tlas.set_transform(instance, moved));
+        assert!(tlas.transform(instance) == Some(moved));
+        // Transformations that cannot be inverted leave the instance where it was
+        let flattened = Matrix4::scaling(Vec3::new(1.0, 0.0, 1.0));
+        assert!(!tlas.set_transform(instance, flattened));
+        assert!(tlas.transform(instance) == Some(moved));
+        tlas.rebuild();
+        assert_eq!(tlas.lights().len(), 1);
+        assert_eq!(pdf_above(&tlas.lights()[0], 10.0), 0.0);
+        assert!(pdf_above(&tlas.lights()[0], -10.0) > 0.0);
+    }
+}
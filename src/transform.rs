@@ -0,0 +1,384 @@
+// transform.rs
+use super::bvh::Aabb;
+use super::hit::{Hit, HitRecord};
+use super::ray::Ray;
+use super::vec::{Point3, Vec3};
+
+use std::ops::Mul;
+use std::sync::Arc;
+
+// Affine transformation as a 4x4 matrix acting on column vectors
+#[derive(Clone, Copy, PartialEq)]
+pub struct Matrix4 {
+    m: [[f64; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn new(m: [[f64; 4]; 4]) -> Matrix4 {
+        Matrix4 { m }
+    }
+
+    pub fn identity() -> Matrix4 {
+        Matrix4::scaling(Vec3::new(1.0, 1.0, 1.0))
+    }
+
+    pub fn translation(offset: Vec3) -> Matrix4 {
+        Matrix4::new([
+            [1.0, 0.0, 0.0, offset.x()],
+            [0.0, 1.0, 0.0, offset.y()],
+            [0.0, 0.0, 1.0, offset.z()],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn scaling(factors: Vec3) -> Matrix4 {
+        Matrix4::new([
+            [factors.x(), 0.0, 0.0, 0.0],
+            [0.0, factors.y(), 0.0, 0.0],
+            [0.0, 0.0, factors.z(), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    // Right-handed rotation by `degrees` about `axis`
+    pub fn rotation(axis: Vec3, degrees: f64) -> Matrix4 {
+        let a = axis.normalized();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let t = 1.0 - cos;
+        Matrix4::new([
+            [
+                t * a.x() * a.x() + cos,
+                t * a.x() * a.y() - sin * a.z(),
+                t * a.x() * a.z() + sin * a.y(),
+                0.0,
+            ],
+            [
+                t * a.x() * a.y() + sin * a.z(),
+                t * a.y() * a.y() + cos,
+                t * a.y() * a.z() - sin * a.x(),
+                0.0,
+            ],
+            [
+                t * a.x() * a.z() - sin * a.y(),
+                t * a.y() * a.z() + sin * a.x(),
+                t * a.z() * a.z() + cos,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = self.m[j][i];
+            }
+        }
+        Matrix4::new(m)
+    }
+
+    // Gauss-Jordan elimination with partial pivoting; None for singular matrices, such as
+    // a scaling by zero
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inverse = Matrix4::identity().m;
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))
+                .unwrap();
+            if a[pivot][column].abs() < 1.0e-12 {
+                return None;
+            }
+            a.swap(column, pivot);
+            inverse.swap(column, pivot);
+
+            let scale = 1.0 / a[column][column];
+            for j in 0..4 {
+                a[column][j] *= scale;
+                inverse[column][j] *= scale;
+            }
+            for row in 0..4 {
+                let factor = a[row][column];
+                if row == column || factor == 0.0 {
+                    continue;
+                }
+                for j in 0..4 {
+                    a[row][j] -= factor * a[column][j];
+                    inverse[row][j] -= factor * inverse[column][j];
+                }
+            }
+        }
+        Some(Matrix4::new(inverse))
+    }
+
+    pub fn transform_point(&self, p: Point3) -> Point3 {
+        self.transform_vector(p) + Vec3::new(self.m[0][3], self.m[1][3], self.m[2][3])
+    }
+
+    // Directions and offsets, which are not affected by the translation
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let row = |i: usize| Vec3::new(self.m[i][0], self.m[i][1], self.m[i][2]).dot(v);
+        Vec3::new(row(0), row(1), row(2))
+    }
+
+    // Whether the transformation preserves angles, i.e. is a rotation, reflection,
+    // translation and uniform scaling at most
+    pub fn is_conformal(&self) -> bool {
+        let column = |j: usize| Vec3::new(self.m[0][j], self.m[1][j], self.m[2][j]);
+        let scale = column(0).dot(column(0));
+        let tolerance = 1.0e-9 * scale;
+        (0..3).all(|i| {
+            (0..3).all(|j| {
+                let expected = if i == j { scale } else { 0.0 };
+                (column(i).dot(column(j)) - expected).abs() <= tolerance
+            })
+        })
+    }
+}
+
+impl Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, other: Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[i][k] * other.m[k][j]).sum();
+            }
+        }
+        Matrix4::new(m)
+    }
+}
+
+// Places a shared object in the world through an affine transformation, so that one heavy
+// mesh can be instanced many times for the cost of a small wrapper each
+pub struct Transformed {
+    object: Arc<dyn Hit>,
+    object_to_world: Matrix4,
+    world_to_object: Matrix4,
+    // Normals transform with the inverse transpose
+    normal_to_world: Matrix4,
+    bounding_box: Option<Aabb>,
+    // Only transformations that preserve angles preserve solid angles, so emissive objects
+    // under any other transformation are left to be found by scattered rays
+    samplable: bool,
+    // Reported as the object id of every hit, telling instances of the same shapes apart
+    instance_id: Option<u32>,
+}
+
+impl Transformed {
+    // None when the transformation cannot be inverted
+    pub fn new(object: Arc<dyn Hit>, object_to_world: Matrix4) -> Option<Transformed> {
+        let world_to_object = object_to_world.inverse()?;
+        let bounding_box = object.bounding_box().map(|bounds| {
+            let corner = |i: usize| {
+                let pick = |axis: usize| {
+                    if i & (1 << axis) == 0 {
+                        bounds.min()[axis]
+                    } else {
+                        bounds.max()[axis]
+                    }
+                };
+                object_to_world.transform_point(Vec3::new(pick(0), pick(1), pick(2)))
+            };
+            (1..8).fold(Aabb::new(corner(0), corner(0)), |bounds, i| {
+                Aabb::surrounding_box(bounds, Aabb::new(corner(i), corner(i))).unwrap()
+            })
+        });
+        Some(Transformed {
+            object,
+            object_to_world,
+            world_to_object,
+            normal_to_world: world_to_object.transpose(),
+            bounding_box,
+            samplable: object_to_world.is_conformal(),
+            instance_id: None,
+        })
+    }
+
+    pub fn with_instance_id(mut self, instance_id: u32) -> Transformed {
+        self.instance_id = Some(instance_id);
+        self
+    }
+
+    pub fn object(&self) -> &Arc<dyn Hit> {
+        &self.object
+    }
+
+    pub fn object_to_world(&self) -> Matrix4 {
+        self.object_to_world
+    }
+
+    // The same ray in object space. The direction is not renormalized, so a hit has the same
+    // ray parameter in both spaces.
+    fn to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.world_to_object.transform_point(ray.origin()),
+            self.world_to_object.transform_vector(ray.direction()),
+        )
+        .with_wavelength(ray.wavelength())
+    }
+}
+
+impl Hit for Transformed {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let mut record = self.object.hit(&self.to_object(ray), t_min, t_max)?;
+        record.point = self.object_to_world.transform_point(record.point);
+        record.normal = self
+            .normal_to_world
+            .transform_vector(record.normal)
+            .normalized();
+        record.geometric_normal = self
+            .normal_to_world
+            .transform_vector(record.geometric_normal)
+            .normalized();
+        record.dpdu = self.object_to_world.transform_vector(record.dpdu);
+        record.dpdv = self.object_to_world.transform_vector(record.dpdv);
+        if let Some(instance_id) = self.instance_id {
+            record.object_id = instance_id;
+        }
+        Some(record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        self.bounding_box
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        if !self.samplable {
+            return 0.0;
+        }
+        self.object.pdf_value(
+            self.world_to_object.transform_point(origin),
+            self.world_to_object.transform_vector(direction),
+        )
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let direction = self
+            .object
+            .random(self.world_to_object.transform_point(origin));
+        self.object_to_world.transform_vector(direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use crate::vec::Color;
+
+    fn sphere() -> Arc<dyn Hit> {
+        let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        Arc::new(Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, material))
+    }
+
+    #[test]
+    fn instances_report_their_id() {
+        let sphere = sphere();
+        let ray = Ray::new(Vec3::new(5.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0));
+        let moved = Matrix4::translation(Vec3::new(5.0, 0.0, 0.0));
+        let plain = Transformed::new(sphere.clone(), moved).unwrap();
+        let shape_id = plain.hit(&ray, 0.001, f64::INFINITY).unwrap().object_id;
+        let instance = Transformed::new(sphere, moved)
+            .unwrap()
+            .with_instance_id(shape_id + 1);
+        let record = instance.hit(&ray, 0.001, f64::INFINITY).unwrap();
+        assert_eq!(record.object_id, shape_id + 1);
+    }
+
+    #[test]
+    fn only_conformal_transforms_are_sampled() {
+        let origin = Vec3::new(0.0, 0.0, -10.0);
+        let direction = Vec3::new(0.0, 0.0, 1.0);
+        let rotated = Matrix4::rotation(Vec3::new(0.0, 1.0, 0.0), 30.0)
+            * Matrix4::scaling(Vec3::new(2.0, 2.0, 2.0));
+        let stretched = Matrix4::scaling(Vec3::new(2.0, 1.0, 1.0));
+        assert!(rotated.is_conformal());
+        assert!(!stretched.is_conformal());
+        let rotated = Transformed::new(sphere(), rotated).unwrap();
+        let stretched = Transformed::new(sphere(), stretched).unwrap();
+        assert!(rotated.pdf_value(origin, direction) > 0.0);
+        assert_eq!(stretched.pdf_value(origin, direction), 0.0);
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-9, "{} instead of {}", a, b);
+    }
+
+    #[test]
+    fn hits_land_on_the_transformed_shape() {
+        // An ellipsoid with its long axis turned onto y, centered at (3, 0, 0)
+        let transform = Matrix4::translation(Vec3::new(3.0, 0.0, 0.0))
+            * Matrix4::rotation(Vec3::new(0.0, 0.0, 1.0), 90.0)
+            * Matrix4::scaling(Vec3::new(2.0, 1.0, 1.0));
+        let ellipsoid = Transformed::new(sphere(), transform).unwrap();
+
+        let down = Ray::new(Vec3::new(3.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let record = ellipsoid.hit(&down, 0.001, f64::INFINITY).unwrap();
+        assert!((record.time - 8.0).abs() < 1e-9);
+        assert_near(record.point, Vec3::new(3.0, 2.0, 0.0));
+        assert_near(record.normal, Vec3::new(0.0, 1.0, 0.0));
+
+        // Ray parameters carry over for directions of any length
+        let across = Ray::new(Vec3::new(-7.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0));
+        let record = ellipsoid.hit(&across, 0.001, f64::INFINITY).unwrap();
+        assert!((record.time - 4.5).abs() < 1e-9);
+        assert_near(record.point, Vec3::new(2.0, 0.0, 0.0));
+        assert_near(record.normal, Vec3::new(-1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn normals_follow_the_inverse_transpose() {
+        let ellipsoid =
+            Transformed::new(sphere(), Matrix4::scaling(Vec3::new(2.0, 1.0, 1.0))).unwrap();
+        // (x / 2)^2 + y^2 = 1 has the gradient (x / 2, 2 y) at (sqrt 2, 1 / sqrt 2), parallel
+        // to (1, 2, 0), where transforming the normal like a direction would give (2, 1, 0)
+        let point = Vec3::new(2.0f64.sqrt(), 0.5f64.sqrt(), 0.0);
+        let normal = Vec3::new(1.0, 2.0, 0.0).normalized();
+        let ray = Ray::new(point + 3.0 * normal, (-1.0) * normal);
+        let record = ellipsoid.hit(&ray, 0.001, f64::INFINITY).unwrap();
+        assert_near(record.point, point);
+        assert_near(record.normal, normal);
+        assert_near(record.geometric_normal, normal);
+        assert!(record.normal.dot(record.dpdu).abs() < 1e-9);
+        assert!(record.normal.dot(record.dpdv).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rotated_boxes_enclose_every_corner() {
+        let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+        // Bounded by the box from (0, 1, 2) to (2, 3, 4)
+        let sphere = Arc::new(Sphere::new(Vec3::new(1.0, 2.0, 3.0), 1.0, material));
+        let transform = Matrix4::translation(Vec3::new(-1.0, 0.5, 2.0))
+            * Matrix4::rotation(Vec3::new(1.0, 1.0, 0.0), 40.0);
+        let bounds = Transformed::new(sphere, transform)
+            .unwrap()
+            .bounding_box()
+            .unwrap();
+
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                let corner = Vec3::new(
+                    (i & 1) as f64 * 2.0,
+                    1.0 + (i >> 1 & 1) as f64 * 2.0,
+                    2.0 + (i >> 2 & 1) as f64 * 2.0,
+                );
+                transform.transform_point(corner)
+            })
+            .collect();
+        for axis in 0..3 {
+            let (min, max) = corners
+                .iter()
+                .map(|corner| corner[axis])
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), x| {
+                    (min.min(x), max.max(x))
+                });
+            // Enclosing every corner, and no larger
+            assert!((bounds.min()[axis] - min).abs() < 1e-9);
+            assert!((bounds.max()[axis] - max).abs() < 1e-9);
+        }
+    }
+}
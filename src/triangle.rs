@@ -0,0 +1,547 @@
+// triangle.rs
+use super::bvh::Aabb;
+use super::hit::{Hit, HitRecord};
+use super::material::Scatter;
+use super::random::random;
+use super::ray::Ray;
+use super::sampling::tangent_frame;
+use super::vec::{Point3, Vec3};
+use std::fmt;
+use std::sync::Arc;
+
+// Padding applied to flat bounding boxes so axis-aligned triangles still have volume
+const BOX_PADDING: f64 = 1.0e-4;
+
+pub struct Triangle {
+    vertices: [Point3; 3],
+    material: Arc<dyn Scatter>,
+    id: u32,
+}
+
+impl Triangle {
+    pub fn new(v0: Point3, v1: Point3, v2: Point3, material: Arc<dyn Scatter>) -> Triangle {
+        Triangle {
+            vertices: [v0, v1, v2],
+            material,
+            id: 0,
+        }
+    }
+
+    // Reported as the object id of hits
+    pub fn with_id(mut self, id: u32) -> Triangle {
+        self.id = id;
+        self
+    }
+}
+
+impl Hit for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let (time, b1, b2) = intersect(&self.vertices, ray, t_min, t_max)?;
+        let [v0, v1, v2] = self.vertices;
+
+        // The barycentric coordinates are the surface coordinates along the two edges
+        let mut record = HitRecord {
+            point: ray.at(time),
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            geometric_normal: Vec3::new(0.0, 0.0, 0.0),
+            material: self.material.clone(),
+            time,
+            front_face: false,
+            u: b1,
+            v: b2,
+            dpdu: v1 - v0,
+            dpdv: v2 - v0,
+            object_id: self.id,
+            // A triangle on its own is the only face of its shape
+            primitive_id: 0,
+        };
+
+        let outward_normal = (v1 - v0).cross(v2 - v0).normalized();
+        record.set_face_normal(ray, outward_normal);
+        Some(record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(triangle_box(&self.vertices))
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        triangle_pdf(&self.vertices, origin, direction)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        sample_triangle(&self.vertices) - origin
+    }
+}
+
+// One face of a TriangleMesh; each index points into the matching mesh buffer
+#[derive(Clone, Copy)]
+pub struct Face {
+    pub positions: [usize; 3],
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
+}
+
+// A face index pointing past the end of the buffer it refers to
+#[derive(Debug)]
+pub struct MeshError {
+    pub face: usize,
+    // "position", "normal" or "uv"
+    pub buffer: &'static str,
+    pub index: usize,
+    pub count: usize,
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "face {}: {} index {} is out of range ({} defined)",
+            self.face, self.buffer, self.index, self.count
+        )
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+pub struct TriangleMesh {
+    positions: Vec<Point3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<[f64; 2]>,
+    faces: Vec<Face>,
+    material: Arc<dyn Scatter>,
+    id: u32,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Point3>,
+        normals: Vec<Vec3>,
+        uvs: Vec<[f64; 2]>,
+        faces: Vec<Face>,
+        material: Arc<dyn Scatter>,
+    ) -> Result<TriangleMesh, MeshError> {
+        // Checked once here, so intersecting a face can index the buffers directly
+        for (index, face) in faces.iter().enumerate() {
+            let buffers = [
+                ("position", Some(face.positions), positions.len()),
+                ("normal", face.normals, normals.len()),
+                ("uv", face.uvs, uvs.len()),
+            ];
+            for (buffer, indices, count) in buffers {
+                if let Some(&out_of_range) = indices.iter().flatten().find(|&&i| i >= count) {
+                    return Err(MeshError {
+                        face: index,
+                        buffer,
+                        index: out_of_range,
+                        count,
+                    });
+                }
+            }
+        }
+        Ok(TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            faces,
+            material,
+            id: 0,
+        })
+    }
+
+    // Reported as the object id of hits on any of its faces
+    pub fn with_id(mut self, id: u32) -> TriangleMesh {
+        self.id = id;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.faces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faces.is_empty()
+    }
+
+    // Splits the mesh into one Hit object per face, ready to be passed to BvhNode::new
+    pub fn triangles(self: &Arc<Self>) -> Vec<Arc<dyn Hit>> {
+        (0..self.faces.len())
+            .map(|face| {
+                Arc::new(MeshTriangle {
+                    mesh: self.clone(),
+                    face,
+                }) as Arc<dyn Hit>
+            })
+            .collect()
+    }
+
+    fn vertices(&self, face: &Face) -> [Point3; 3] {
+        face.positions.map(|index| self.positions[index])
+    }
+}
+
+pub struct MeshTriangle {
+    mesh: Arc<TriangleMesh>,
+    face: usize,
+}
+
+impl Hit for MeshTriangle {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        let face = &self.mesh.faces[self.face];
+        let vertices = self.mesh.vertices(face);
+        let (time, b1, b2) = intersect(&vertices, ray, t_min, t_max)?;
+        let b0 = 1.0 - b1 - b2;
+
+        let [v0, v1, v2] = vertices;
+        let (u, v, dpdu, dpdv) = match face.uvs {
+            Some(indices) => {
+                let [uv0, uv1, uv2] = indices.map(|index| self.mesh.uvs[index]);
+                let (dpdu, dpdv) = uv_tangents([v0, v1, v2], [uv0, uv1, uv2]);
+                (
+                    b0 * uv0[0] + b1 * uv1[0] + b2 * uv2[0],
+                    b0 * uv0[1] + b1 * uv1[1] + b2 * uv2[1],
+                    dpdu,
+                    dpdv,
+                )
+            }
+            None => (b1, b2, v1 - v0, v2 - v0),
+        };
+
+        let mut record = HitRecord {
+            point: ray.at(time),
+            normal: Vec3::new(0.0, 0.0, 0.0),
+            geometric_normal: Vec3::new(0.0, 0.0, 0.0),
+            material: self.mesh.material.clone(),
+            time,
+            front_face: false,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            object_id: self.mesh.id,
+            primitive_id: self.face as u32,
+        };
+
+        let outward_normal = (v1 - v0).cross(v2 - v0).normalized();
+        record.set_face_normal(ray, outward_normal);
+
+        // Interpolated vertex normals only change the shading, not which side was hit
+        if let Some(indices) = face.normals {
+            let [n0, n1, n2] = indices.map(|index| self.mesh.normals[index]);
+            record.set_shading_normal((b0 * n0 + b1 * n1 + b2 * n2).normalized());
+        }
+
+        Some(record)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let face = &self.mesh.faces[self.face];
+        Some(triangle_box(&self.mesh.vertices(face)))
+    }
+
+    fn pdf_value(&self, origin: Point3, direction: Vec3) -> f64 {
+        let face = &self.mesh.faces[self.face];
+        triangle_pdf(&self.mesh.vertices(face), origin, direction)
+    }
+
+    fn random(&self, origin: Point3) -> Vec3 {
+        let face = &self.mesh.faces[self.face];
+        sample_triangle(&self.mesh.vertices(face)) - origin
+    }
+}
+
+// Möller–Trumbore ray/triangle intersection, returns the hit time and barycentric coordinates
+fn intersect(vertices: &[Point3; 3], ray: &Ray, t_min: f64, t_max: f64) -> Option<(f64, f64, f64)> {
+    // The determinant is |d| |e1| |e2| times the sines and cosines of the angles between them,
+    // so relative to those lengths it only depends on how degenerate the triangle is and how
+    // closely the ray grazes it, not on the size of the mesh
+    const EPS: f64 = 1.0e-12;
+    let [v0, v1, v2] = *vertices;
+
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let pvec = ray.direction().cross(edge2);
+    let determinant = edge1.dot(pvec);
+    let scale = ray.direction().length() * edge1.length() * edge2.length();
+    if determinant.abs() <= EPS * scale {
+        return None;
+    }
+    let inv_determinant = 1.0 / determinant;
+
+    let tvec = ray.origin() - v0;
+    let b1 = tvec.dot(pvec) * inv_determinant;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+
+    let qvec = tvec.cross(edge1);
+    let b2 = ray.direction().dot(qvec) * inv_determinant;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+
+    let time = edge2.dot(qvec) * inv_determinant;
+    if time < t_min || t_max < time {
+        return None;
+    }
+
+    Some((time, b1, b2))
+}
+
+// Derivatives of the position with respect to the texture coordinates, solved from the
+// edges of the triangle in both spaces. Degenerate texture coordinates get an arbitrary frame.
+fn uv_tangents(vertices: [Point3; 3], uvs: [[f64; 2]; 3]) -> (Vec3, Vec3) {
+    let [v0, v1, v2] = vertices;
+    let [uv0, uv1, uv2] = uvs;
+    let (du02, dv02) = (uv0[0] - uv2[0], uv0[1] - uv2[1]);
+    let (du12, dv12) = (uv1[0] - uv2[0], uv1[1] - uv2[1]);
+    let (dp02, dp12) = (v0 - v2, v1 - v2);
+
+    let determinant = du02 * dv12 - dv02 * du12;
+    if determinant.abs() < 1.0e-12 {
+        return tangent_frame((v1 - v0).cross(v2 - v0).normalized());
+    }
+    let inverse = 1.0 / determinant;
+    (
+        inverse * (dv12 * dp02 - dv02 * dp12),
+        inverse * (du02 * dp12 - du12 * dp02),
+    )
+}
+
+// Uniformly distributed point on the triangle
+fn sample_triangle(vertices: &[Point3; 3]) -> Point3 {
+    let [v0, v1, v2] = *vertices;
+    let (mut b1, mut b2) = (random(), random());
+    // Fold the far half of the unit square back onto the triangle
+    if b1 + b2 > 1.0 {
+        b1 = 1.0 - b1;
+        b2 = 1.0 - b2;
+    }
+    v0 + b1 * (v1 - v0) + b2 * (v2 - v0)
+}
+
+// Density of `sample_triangle` as seen from `origin`, converted from area to solid angle
+fn triangle_pdf(vertices: &[Point3; 3], origin: Point3, direction: Vec3) -> f64 {
+    let ray = Ray::new(origin, direction);
+    let Some((time, _, _)) = intersect(vertices, &ray, 0.001, f64::INFINITY) else {
+        return 0.0;
+    };
+    let [v0, v1, v2] = *vertices;
+    let cross = (v1 - v0).cross(v2 - v0);
+    let area = 0.5 * cross.length();
+    let distance_squared = (time * direction.length()).powi(2);
+    let cosine = cross.normalized().dot(direction.normalized()).abs();
+    if cosine <= 0.0 {
+        return 0.0;
+    }
+    distance_squared / (cosine * area)
+}
+
+fn triangle_box(vertices: &[Point3; 3]) -> Aabb {
+    let [v0, v1, v2] = *vertices;
+    let padding = Vec3::new(BOX_PADDING, BOX_PADDING, BOX_PADDING);
+    let min = Vec3::new(
+        v0.x().min(v1.x()).min(v2.x()),
+        v0.y().min(v1.y()).min(v2.y()),
+        v0.z().min(v1.z()).min(v2.z()),
+    );
+    let max = Vec3::new(
+        v0.x().max(v1.x()).max(v2.x()),
+        v0.y().max(v1.y()).max(v2.y()),
+        v0.z().max(v1.z()).max(v2.z()),
+    );
+    Aabb::new(min - padding, max + padding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::vec::Color;
+
+    fn mesh(normals: usize, uvs: usize, face: Face) -> Result<TriangleMesh, MeshError> {
+        TriangleMesh::new(
+            vec![
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+            ],
+            vec![Vec3::new(0.0, 0.0, 1.0); normals],
+            vec![[0.0, 0.0]; uvs],
+            vec![face],
+            Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5))),
+        )
+    }
+
+    #[test]
+    fn face_indices_must_lie_within_their_buffers() {
+        let face = Face {
+            positions: [0, 1, 2],
+            normals: Some([0, 0, 0]),
+            uvs: Some([0, 1, 0]),
+        };
+        assert!(mesh(1, 2, face).is_ok());
+        // Faces without normals or uvs need none
+        let bare = Face {
+            normals: None,
+            uvs: None,
+            ..face
+        };
+        assert!(mesh(0, 0, bare).is_ok());
+
+        for (normals, uvs, face, buffer, index, count) in [
+            (
+                1,
+                2,
+                Face {
+                    positions: [0, 3, 2],
+                    ..face
+                },
+                "position",
+                3,
+                3,
+            ),
+            (0, 2, face, "normal", 0, 0),
+            (1, 1, face, "uv", 1, 1),
+        ] {
+            let error = mesh(normals, uvs, face).err().unwrap();
+            assert_eq!(
+                (error.face, error.buffer, error.index, error.count),
+                (0, buffer, index, count)
+            );
+        }
+    }
+
+    fn white() -> Arc<dyn Scatter> {
+        Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)))
+    }
+
+    // The unit right triangle in the z = 0 plane, scaled by `size`
+    fn triangle(size: f64) -> Triangle {
+        Triangle::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(size, 0.0, 0.0),
+            Point3::new(0.0, size, 0.0),
+            white(),
+        )
+    }
+
+    // Straight down onto (x, y, 0) from one unit above
+    fn down(x: f64, y: f64) -> Ray {
+        Ray::new(Point3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0))
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-12, "{} instead of {}", a, b);
+    }
+
+    #[test]
+    fn rays_hit_inside_and_miss_outside() {
+        let triangle = triangle(1.0);
+        let record = triangle
+            .hit(&down(0.25, 0.5), 0.001, f64::INFINITY)
+            .unwrap();
+        assert_eq!(record.time, 1.0);
+        assert_near(record.point, Point3::new(0.25, 0.5, 0.0));
+        assert_eq!((record.u, record.v), (0.25, 0.5));
+        assert!(record.front_face);
+        assert_near(record.normal, Vec3::new(0.0, 0.0, 1.0));
+
+        for (x, y) in [(0.75, 0.75), (-0.1, 0.5), (0.5, -0.1), (2.0, 2.0)] {
+            assert!(triangle.hit(&down(x, y), 0.001, f64::INFINITY).is_none());
+        }
+        // Outside the interval of the ray
+        assert!(triangle.hit(&down(0.25, 0.25), 0.001, 0.5).is_none());
+        assert!(triangle
+            .hit(&down(0.25, 0.25), 1.5, f64::INFINITY)
+            .is_none());
+        // Parallel to the plane of the triangle
+        let grazing = Ray::new(Point3::new(-1.0, 0.25, 0.0), Vec3::new(1.0, 0.0, 0.0));
+        assert!(triangle.hit(&grazing, 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn edges_and_vertices_are_part_of_the_triangle() {
+        let triangle = triangle(1.0);
+        for (x, y, u, v) in [
+            (0.5, 0.0, 0.5, 0.0),
+            (0.0, 0.5, 0.0, 0.5),
+            (0.5, 0.5, 0.5, 0.5),
+            (0.0, 0.0, 0.0, 0.0),
+            (1.0, 0.0, 1.0, 0.0),
+            (0.0, 1.0, 0.0, 1.0),
+        ] {
+            let record = triangle.hit(&down(x, y), 0.001, f64::INFINITY);
+            let record = record.unwrap_or_else(|| panic!("({}, {}) was missed", x, y));
+            assert_eq!((record.u, record.v), (u, v));
+        }
+    }
+
+    #[test]
+    fn back_faces_are_hit_with_the_normal_against_the_ray() {
+        let ray = Ray::new(Point3::new(0.25, 0.25, -2.0), Vec3::new(0.0, 0.0, 1.0));
+        let record = triangle(1.0).hit(&ray, 0.001, f64::INFINITY).unwrap();
+        assert_eq!(record.time, 2.0);
+        assert!(!record.front_face);
+        assert_near(record.normal, Vec3::new(0.0, 0.0, -1.0));
+        assert_near(record.geometric_normal, Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    #[test]
+    fn mesh_faces_interpolate_their_vertex_data() {
+        let mesh = TriangleMesh::new(
+            vec![
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 0.0, 0.0),
+                Point3::new(0.0, 1.0, 0.0),
+            ],
+            vec![
+                Vec3::new(0.0, 0.0, 1.0),
+                Vec3::new(1.0, 0.0, 1.0).normalized(),
+                Vec3::new(0.0, 1.0, 1.0).normalized(),
+            ],
+            vec![[0.5, 0.5], [1.0, 0.5], [0.5, 1.0]],
+            vec![Face {
+                positions: [0, 1, 2],
+                normals: Some([0, 1, 2]),
+                uvs: Some([0, 1, 2]),
+            }],
+            white(),
+        )
+        .unwrap();
+        let triangles = Arc::new(mesh.with_id(4)).triangles();
+        let record = triangles[0]
+            .hit(&down(0.25, 0.5), 0.001, f64::INFINITY)
+            .unwrap();
+
+        // Barycentric coordinates (0.25, 0.25, 0.5)
+        assert!((record.u - 0.625).abs() < 1e-12 && (record.v - 0.75).abs() < 1e-12);
+        let blend = 0.25 * Vec3::new(0.0, 0.0, 1.0)
+            + 0.25 * Vec3::new(1.0, 0.0, 1.0).normalized()
+            + 0.5 * Vec3::new(0.0, 1.0, 1.0).normalized();
+        assert_near(record.normal, blend.normalized());
+        assert_near(record.geometric_normal, Vec3::new(0.0, 0.0, 1.0));
+        assert_near(record.dpdu, Vec3::new(2.0, 0.0, 0.0));
+        assert_near(record.dpdv, Vec3::new(0.0, 2.0, 0.0));
+        assert_eq!((record.object_id, record.primitive_id), (4, 0));
+    }
+
+    #[test]
+    fn degeneracy_does_not_depend_on_the_size() {
+        // Tiny and huge triangles are hit alike
+        for size in [1.0e-7, 1.0e7] {
+            let ray = down(0.25 * size, 0.25 * size);
+            assert!(triangle(size).hit(&ray, 0.0, f64::INFINITY).is_some());
+        }
+        // A huge sliver, its third vertex only a hair off the line through the other two
+        let sliver = Triangle::new(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0e6, 0.0, 0.0),
+            Point3::new(2.0e6, 1.0e-9, 0.0),
+            white(),
+        );
+        assert!(sliver
+            .hit(&down(1.5e6, 0.0), 0.001, f64::INFINITY)
+            .is_none());
+    }
+}
@@ -5,7 +5,7 @@ use std::ops::{
     Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, MulAssign, Range, Sub, SubAssign,
 };
 
-use rand::prelude::*;
+use super::random::random_range;
 
 #[derive(Clone, Copy)]
 pub struct Vec3 {
@@ -54,13 +54,11 @@ impl Vec3 {
     }
 
     pub fn random(domain: Range<f64>) -> Vec3 {
-        let mut rng = rand::thread_rng();
-
         Vec3 {
             e: [
-                rng.gen_range(domain.clone()),
-                rng.gen_range(domain.clone()),
-                rng.gen_range(domain.clone()),
+                random_range(domain.clone()),
+                random_range(domain.clone()),
+                random_range(domain.clone()),
             ],
         }
     }
@@ -84,10 +82,8 @@ impl Vec3 {
     }
 
     pub fn random_in_unit_disk() -> Vec3 {
-        let mut rng = rand::thread_rng();
-
         loop {
-            let point = Vec3::new(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), 0.0);
+            let point = Vec3::new(random_range(-1.0..1.0), random_range(-1.0..1.0), 0.0);
             if point.length() < 1.0 {
                 return point;
             }
@@ -99,6 +95,11 @@ impl Vec3 {
         self[0].abs() < EPS && self[1].abs() < EPS && self[2].abs() < EPS
     }
 
+    // Relative luminance of a linear Rec. 709 color
+    pub fn luminance(self) -> f64 {
+        0.2126 * self[0] + 0.7152 * self[1] + 0.0722 * self[2]
+    }
+
     pub fn invert(self) -> Vec3 {
         Vec3 {
             e: [1.0 / self[0], 1.0 / self[1], 1.0 / self[2]],
@@ -115,23 +116,6 @@ impl Vec3 {
         let r_out_parallel = -(1.0 - r_out_perp.length().powi(2)).abs().sqrt() * n;
         r_out_perp + r_out_parallel
     }
-
-    pub fn format_color(self, samples_per_pixel: u64) -> String {
-        let ir = (256.0
-            * (self[0] / (samples_per_pixel as f64))
-                .sqrt()
-                .clamp(0.0, 0.999)) as u64;
-        let ig = (256.0
-            * (self[1] / (samples_per_pixel as f64))
-                .sqrt()
-                .clamp(0.0, 0.999)) as u64;
-        let ib = (256.0
-            * (self[2] / (samples_per_pixel as f64))
-                .sqrt()
-                .clamp(0.0, 0.999)) as u64;
-
-        format!("{} {} {}", ir, ig, ib)
-    }
 }
 
 impl Index<usize> for Vec3 {
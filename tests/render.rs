@@ -0,0 +1,207 @@
+// render.rs
+// Renders through the public library API, the way embedding applications use it
+use std::sync::Arc;
+
+use rust_raytracer::bvh::LinearBvh;
+use rust_raytracer::camera::Camera;
+use rust_raytracer::environment::SolidColor;
+use rust_raytracer::hit::Hit;
+use rust_raytracer::image::Image;
+use rust_raytracer::material::{DiffuseLight, Lambertian};
+use rust_raytracer::render::{RenderError, RenderSettings, Renderer};
+use rust_raytracer::sphere::Sphere;
+use rust_raytracer::vec::{Color, Point3, Vec3};
+
+fn settings(width: usize, height: usize) -> RenderSettings {
+    RenderSettings {
+        width,
+        height,
+        samples_per_pixel: 4,
+        max_depth: 4,
+        seed: 1,
+        spectral: false,
+    }
+}
+
+fn camera(aspect_ratio: f64) -> Camera {
+    Camera::new(
+        Point3::new(0.0, 0.0, 5.0),
+        Point3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        aspect_ratio,
+        0.0,
+        5.0,
+    )
+}
+
+// A grey sphere filling the middle of the view
+fn world() -> Arc<dyn Hit> {
+    let material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    Arc::new(Sphere::new(Point3::new(0.0, 0.0, 0.0), 1.0, material))
+}
+
+fn renderer(width: usize, height: usize) -> Result<Renderer, RenderError> {
+    let aspect_ratio = width as f64 / height as f64;
+    Renderer::new(camera(aspect_ratio), world(), settings(width, height))
+}
+
+#[test]
+fn images_smaller_than_two_pixels_are_rejected() {
+    for (width, height) in [(0, 8), (8, 0), (1, 8), (8, 1), (1, 1)] {
+        match renderer(width, height) {
+            Err(RenderError::ImageTooSmall {
+                width: w,
+                height: h,
+            }) => {
+                assert_eq!((w, h), (width, height))
+            }
+            _ => panic!("{}x{} pixels were accepted", width, height),
+        }
+    }
+    assert!(renderer(2, 2).is_ok());
+}
+
+#[test]
+fn images_without_samples_are_rejected() {
+    let mut settings = settings(8, 8);
+    settings.samples_per_pixel = 0;
+    assert!(matches!(
+        Renderer::new(camera(1.0), world(), settings),
+        Err(RenderError::NoSamples)
+    ));
+}
+
+#[test]
+fn images_have_the_requested_size() {
+    // Wider than a tile, so the image is put together from several
+    let image = renderer(40, 3).unwrap().render();
+    assert_eq!((image.width(), image.height()), (40, 3));
+    assert_eq!(image.pixels().len(), 40 * 3);
+}
+
+#[test]
+fn rays_that_miss_see_the_environment() {
+    let sky = Color::new(0.2, 0.4, 0.8);
+    let image = renderer(16, 16)
+        .unwrap()
+        .with_environment(Arc::new(SolidColor::new(sky)))
+        .render();
+    let corner = image.pixel(0, 0);
+    for channel in 0..3 {
+        assert!((corner[channel] - sky[channel]).abs() < 1e-12);
+    }
+    // The sphere only reflects half of the light reaching it
+    let center = image.pixel(8, 8);
+    assert!(center[2] < sky[2]);
+}
+
+#[test]
+fn renders_are_reproducible() {
+    let render = || renderer(40, 40).unwrap().render();
+    let (first, second) = (render(), render());
+    for (a, b) in first.pixels().iter().zip(second.pixels()) {
+        for channel in 0..3 {
+            assert_eq!(a[channel].to_bits(), b[channel].to_bits());
+        }
+    }
+}
+
+#[test]
+fn progress_counts_every_tile() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counted = calls.clone();
+    renderer(40, 40)
+        .unwrap()
+        .on_progress(move |_, total| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            assert_eq!(total, 4);
+        })
+        .render();
+    assert_eq!(calls.load(Ordering::Relaxed), 4);
+}
+
+#[test]
+fn background_is_a_solid_environment() {
+    let sky = Color::new(0.2, 0.4, 0.8);
+    let background = renderer(8, 8).unwrap().with_background(sky).render();
+    let environment = renderer(8, 8)
+        .unwrap()
+        .with_environment(Arc::new(SolidColor::new(sky)))
+        .render();
+    for (a, b) in background.pixels().iter().zip(environment.pixels()) {
+        for channel in 0..3 {
+            assert_eq!(a[channel].to_bits(), b[channel].to_bits());
+        }
+    }
+}
+
+#[test]
+fn renders_do_not_depend_on_the_thread_count() {
+    let render = |threads: usize| {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        // Many tiles, so several threads really do share the work
+        pool.install(|| renderer(150, 70).unwrap().render())
+    };
+    let serial = render(1);
+    for threads in [2, 4, 7] {
+        let parallel = render(threads);
+        for (a, b) in serial.pixels().iter().zip(parallel.pixels()) {
+            for channel in 0..3 {
+                assert_eq!(a[channel].to_bits(), b[channel].to_bits());
+            }
+        }
+    }
+}
+
+// Camera at `look_from` looking at the origin, with a black environment so that all light has
+// to come from emissive shapes
+fn lit_by(world: Arc<dyn Hit>, look_from: Point3, lights: Vec<Arc<dyn Hit>>) -> Image {
+    let camera = Camera::new(
+        look_from,
+        Point3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        90.0,
+        1.0,
+        0.0,
+        1.0,
+    );
+    Renderer::new(camera, world, settings(16, 16))
+        .unwrap()
+        .with_environment(Arc::new(SolidColor::new(Color::new(0.0, 0.0, 0.0))))
+        .with_lights(lights)
+        .render()
+}
+
+#[test]
+fn emissive_shapes_light_the_scene() {
+    let light: Arc<dyn Hit> = Arc::new(Sphere::new(
+        Point3::new(0.0, 0.0, 0.0),
+        1.0,
+        Arc::new(DiffuseLight::new(Color::new(4.0, 4.0, 4.0))),
+    ));
+    let floor: Arc<dyn Hit> = Arc::new(Sphere::new(
+        Point3::new(0.0, -101.1, 0.0),
+        100.0,
+        Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5))),
+    ));
+    let world: Arc<dyn Hit> = Arc::new(LinearBvh::new(vec![light.clone(), floor]));
+
+    // With and without sampling the light directly
+    for lights in [Vec::new(), vec![light.clone()]] {
+        let image = lit_by(world.clone(), Point3::new(0.0, 0.0, 3.0), lights);
+        // The light itself, the floor below it and the empty sky above
+        assert_eq!(image.pixel(8, 8).x(), 4.0);
+        let floor: f64 = (0..16).map(|x| image.pixel(x, 15).x()).sum();
+        assert!(floor > 0.0);
+        assert_eq!(image.pixel(8, 0).x(), 0.0);
+    }
+
+    // Both faces emit, so the light is just as bright from inside
+    let image = lit_by(light, Point3::new(0.0, 0.0, 0.1), Vec::new());
+    assert!(image.pixels().iter().all(|color| color.x() == 4.0));
+}